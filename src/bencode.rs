@@ -0,0 +1,209 @@
+//! Converting between bencode and JSON values, for displaying and editing
+//! arbitrary bencoded data.
+
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+
+/// Key of the single-entry object standing in for a byte string that is not
+/// valid UTF-8, such as the piece hashes of a torrent.
+pub const BINARY_KEY: &str = "$hex";
+
+/// Decodes the bencoded value at the start of `encoded`, returning it and the
+/// bytes after it. Strings that are not UTF-8 decode to `{"$hex": "..."}`.
+pub fn decode_bencoded_bytes(encoded: &[u8]) -> Result<(Value, &[u8])> {
+    match encoded.first() {
+        Some(b'i') => {
+            let end = find(encoded, b'e').context("unterminated integer")?;
+            let n = parse_integer(&encoded[1..end]).with_context(|| {
+                format!(
+                    "invalid integer {}",
+                    String::from_utf8_lossy(&encoded[1..end])
+                )
+            })?;
+            Ok((n.into(), &encoded[end + 1..]))
+        }
+        Some(b'l') => {
+            let mut items = vec![];
+            let mut rest = &encoded[1..];
+            while rest.first() != Some(&b'e') {
+                let (v, remainder) = decode_bencoded_bytes(rest)?;
+                items.push(v);
+                rest = remainder;
+            }
+            Ok((items.into(), &rest[1..]))
+        }
+        Some(b'd') => {
+            let mut items = serde_json::Map::new();
+            let mut rest = &encoded[1..];
+            while rest.first() != Some(&b'e') {
+                let (k, remainder) = decode_bencoded_bytes(rest)?;
+                let k = match k {
+                    Value::String(k) => k,
+                    _ => bail!("Dictionary keys must be strings, found: {}", k),
+                };
+                let (v, remainder) = decode_bencoded_bytes(remainder)?;
+                items.insert(k, v);
+                rest = remainder;
+            }
+            Ok((items.into(), &rest[1..]))
+        }
+        Some(b'0'..=b'9') => {
+            let colon = find(encoded, b':').context("string length without ':'")?;
+            let len = std::str::from_utf8(&encoded[..colon])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .context("invalid string length")?;
+            let rest = &encoded[colon + 1..];
+            anyhow::ensure!(
+                rest.len() >= len,
+                "string of {len} bytes, but only {} remain",
+                rest.len()
+            );
+            let (bytes, rest) = rest.split_at(len);
+            let value = match std::str::from_utf8(bytes) {
+                Ok(s) => s.into(),
+                Err(_) => serde_json::json!({ BINARY_KEY: hex::encode(bytes) }),
+            };
+            Ok((value, rest))
+        }
+        _ => bail!(
+            "Invalid bencoded value: {}",
+            String::from_utf8_lossy(encoded)
+        ),
+    }
+}
+
+/// Like [`decode_bencoded_bytes`], for input that is known to be text.
+pub fn decode_bencoded_value(encoded_value: &str) -> Result<(Value, &str)> {
+    let (value, rest) = decode_bencoded_bytes(encoded_value.as_bytes())?;
+    let rest = encoded_value
+        .get(encoded_value.len() - rest.len()..)
+        .context("value ends inside a UTF-8 character")?;
+    Ok((value, rest))
+}
+
+/// Encodes `value` as bencode, the inverse of [`decode_bencoded_bytes`].
+/// Dictionary keys are sorted by their raw bytes, as the spec requires.
+pub fn encode_bencoded_value(value: &Value) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out)?;
+    Ok(out)
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) -> Result<()> {
+    match value {
+        Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                out.extend(format!("i{n}e").bytes());
+            } else if let Some(n) = n.as_u64() {
+                out.extend(format!("i{n}e").bytes());
+            } else {
+                bail!("bencode has no floats, cannot encode {n}");
+            }
+        }
+        Value::String(s) => encode_bytes(s.as_bytes(), out),
+        Value::Array(items) => {
+            out.push(b'l');
+            for item in items {
+                encode_into(item, out)?;
+            }
+            out.push(b'e');
+        }
+        Value::Object(map) => {
+            if let Some(bytes) = binary(map)? {
+                encode_bytes(&bytes, out);
+                return Ok(());
+            }
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+            out.push(b'd');
+            for (k, v) in entries {
+                encode_bytes(k.as_bytes(), out);
+                encode_into(v, out)?;
+            }
+            out.push(b'e');
+        }
+        Value::Null => bail!("bencode has no null"),
+        Value::Bool(b) => bail!("bencode has no booleans, cannot encode {b}"),
+    }
+    Ok(())
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(format!("{}:", bytes.len()).bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// The bytes of a `{"$hex": "..."}` stand-in, if `map` is one.
+fn binary(map: &serde_json::Map<String, Value>) -> Result<Option<Vec<u8>>> {
+    match (map.len(), map.get(BINARY_KEY)) {
+        (1, Some(Value::String(hex))) => hex::decode(hex)
+            .with_context(|| format!("decode {BINARY_KEY} string"))
+            .map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Parses the body of `i<n>e`. Bencode allows one spelling per number, so
+/// empty bodies, leading zeros and `-0` are rejected.
+fn parse_integer(digits: &[u8]) -> Option<i64> {
+    let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+    let canonical = match magnitude {
+        [] => false,
+        [b'0'] => magnitude.len() == digits.len(),
+        [b'0', ..] => false,
+        _ => magnitude.iter().all(u8::is_ascii_digit),
+    };
+    if !canonical {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn find(haystack: &[u8], byte: u8) -> Option<usize> {
+    haystack.iter().position(|&b| b == byte)
+}
+
+#[test]
+fn binary_strings_decode_to_hex() {
+    let (value, rest) =
+        decode_bencoded_bytes(b"d4:name4:spam6:pieces4:\xff\x00\x12\xabe5:extra").unwrap();
+    assert_eq!(
+        value,
+        serde_json::json!({ "name": "spam", "pieces": { "$hex": "ff0012ab" } })
+    );
+    assert_eq!(rest, b"5:extra");
+    assert!(decode_bencoded_bytes(b"10:short").is_err());
+}
+
+#[test]
+fn integers_must_be_canonical() {
+    let int = |s: &[u8]| decode_bencoded_bytes(s).ok().map(|(v, _)| v);
+    assert_eq!(int(b"i0e"), Some(0.into()));
+    assert_eq!(int(b"i-1e"), Some((-1).into()));
+    assert_eq!(int(b"i42e"), Some(42.into()));
+    assert_eq!(int(b"i-0e"), None);
+    assert_eq!(int(b"i03e"), None);
+    assert_eq!(int(b"i-03e"), None);
+    assert_eq!(int(b"ie"), None);
+    assert_eq!(int(b"i-e"), None);
+    assert_eq!(int(b"i+1e"), None);
+}
+
+#[test]
+fn reencoded_info_matches_info_hash() {
+    use sha1::Digest;
+
+    let dot_torrent = include_bytes!("../sample.torrent");
+    let t: crate::torrent::Torrent = serde_bencode::from_bytes(dot_torrent).unwrap();
+    let (value, rest) = decode_bencoded_bytes(dot_torrent).unwrap();
+    assert!(rest.is_empty());
+
+    let info = encode_bencoded_value(&value["info"]).unwrap();
+    let hash: [u8; 20] = sha1::Sha1::digest(&info).into();
+    assert_eq!(hash, t.info_hash());
+    assert_eq!(encode_bencoded_value(&value).unwrap(), dot_torrent);
+
+    assert!(encode_bencoded_value(&serde_json::json!(1.5)).is_err());
+    assert!(encode_bencoded_value(&serde_json::json!([null])).is_err());
+}
@@ -0,0 +1,133 @@
+//! Checking a download against a checksum published next to it, such as the
+//! `SHA256SUMS` many distributions ship.
+
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// An expected digest of a whole download. The algorithm follows from the
+/// length of the hex digest, or from an `sha1:` / `sha256:` prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+impl FromStr for Checksum {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, digest) = match s.split_once(':') {
+            Some((algorithm, digest)) => (Some(algorithm), digest),
+            None => (None, s),
+        };
+        let digest = hex::decode(digest).context("checksum is not hex")?;
+        match (algorithm, digest.len()) {
+            (None | Some("sha1"), 20) => Ok(Self::Sha1(digest.try_into().unwrap())),
+            (None | Some("sha256"), 32) => Ok(Self::Sha256(digest.try_into().unwrap())),
+            (Some(algorithm @ ("sha1" | "sha256")), len) => {
+                anyhow::bail!("{len}-byte digest does not fit {algorithm}")
+            }
+            (Some(algorithm), _) => anyhow::bail!("unsupported checksum algorithm {algorithm}"),
+            (None, len) => anyhow::bail!("no supported checksum has a {len}-byte digest"),
+        }
+    }
+}
+
+impl Checksum {
+    /// Fails unless `data` has this digest.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.verify()
+    }
+
+    /// Checks data fed in pieces, e.g. read back from disk a chunk at a time.
+    pub fn hasher(&self) -> ChecksumHasher {
+        let state = match self {
+            Self::Sha1(_) => HasherState::Sha1(Sha1::new()),
+            Self::Sha256(_) => HasherState::Sha256(Sha256::new()),
+        };
+        ChecksumHasher {
+            expected: self.clone(),
+            state,
+        }
+    }
+}
+
+/// A [`Checksum`] being computed over data fed to [`ChecksumHasher::update`].
+pub struct ChecksumHasher {
+    expected: Checksum,
+    state: HasherState,
+}
+
+enum HasherState {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            HasherState::Sha1(hasher) => hasher.update(data),
+            HasherState::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Fails unless the data fed so far has the expected digest.
+    pub fn verify(self) -> Result<()> {
+        let actual = match self.state {
+            HasherState::Sha1(hasher) => hasher.finalize().to_vec(),
+            HasherState::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        let expected = match &self.expected {
+            Checksum::Sha1(expected) => &expected[..],
+            Checksum::Sha256(expected) => &expected[..],
+        };
+        anyhow::ensure!(
+            expected == actual,
+            "checksum mismatch: expected {}, got {}",
+            hex::encode(expected),
+            hex::encode(actual)
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn checksum_algorithm_follows_digest() {
+    assert!(matches!("ab".repeat(20).parse(), Ok(Checksum::Sha1(_))));
+    assert!(matches!("ab".repeat(32).parse(), Ok(Checksum::Sha256(_))));
+    assert!(
+        format!("sha1:{}", "ab".repeat(32))
+            .parse::<Checksum>()
+            .is_err()
+    );
+    assert!(
+        format!("md5:{}", "ab".repeat(16))
+            .parse::<Checksum>()
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn download_is_checked_against_external_checksum() {
+    use crate::download::{DownloadConfig, download_all_single_peer};
+
+    let data: Vec<u8> = (0..70_000u32).map(|i| (i % 211) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+    let downloaded = download_all_single_peer(t, seeder, &DownloadConfig::default())
+        .await
+        .expect("download from the mock seeder");
+
+    let correct = Checksum::Sha256(Sha256::digest(&data).into());
+    correct
+        .verify(downloaded.bytes())
+        .expect("checksum of the data");
+    let wrong = Checksum::Sha256(Sha256::digest(b"something else").into());
+    let err = wrong.verify(downloaded.bytes()).unwrap_err().to_string();
+    assert!(err.contains("checksum mismatch"), "{err}");
+}
@@ -0,0 +1,167 @@
+//! Reading and writing a torrent's data where it lives in the torrent's
+//! files, whichever files a piece spans.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    download::{DownloadConfig, open_output},
+    torrent::Torrent,
+};
+
+/// The output files of a torrent, each with the offset in the torrent's data
+/// where it starts.
+pub(crate) struct DiskWriter {
+    files: Vec<(usize, usize, tokio::fs::File)>,
+}
+
+impl DiskWriter {
+    /// Creates the files of `t` at [`Torrent::output_paths`]. Each file is
+    /// sized up front, so pieces can be written in any order. Files already
+    /// there are kept, not truncated, when [`DownloadConfig::have`] is set.
+    pub(crate) async fn create(
+        t: &Torrent,
+        output: &Path,
+        config: &DownloadConfig,
+    ) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut start = 0;
+        for (file, path) in t.files().into_iter().zip(t.output_paths(output)?) {
+            if let Some(dir) = path.parent()
+                && !dir.as_os_str().is_empty()
+            {
+                tokio::fs::create_dir_all(dir)
+                    .await
+                    .with_context(|| format!("create directory {}", dir.display()))?;
+            }
+            let out = open_output(&path, config, config.have.is_none()).await?;
+            out.set_len(file.length as u64)
+                .await
+                .with_context(|| format!("size {}", path.display()))?;
+            files.push((start, file.length, out));
+            start += file.length;
+        }
+        Ok(Self { files })
+    }
+
+    /// Writes `bytes`, which start at `offset` in the torrent's data, into
+    /// the files they span.
+    pub(crate) async fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        let end = offset + bytes.len();
+        for (start, length, file) in &mut self.files {
+            let (from, to) = (offset.max(*start), end.min(*start + *length));
+            if from >= to {
+                continue;
+            }
+            file.seek(std::io::SeekFrom::Start((from - *start) as u64))
+                .await
+                .context("seek to piece offset")?;
+            file.write_all(&bytes[from - offset..to - offset])
+                .await
+                .context("write piece")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        for (_, _, file) in &mut self.files {
+            file.flush().await.context("flush output file")?;
+        }
+        Ok(())
+    }
+}
+
+/// The files of a torrent that may be partly or wholly missing, e.g. an
+/// interrupted download.
+pub(crate) struct DiskReader {
+    files: Vec<(usize, usize, Option<tokio::fs::File>)>,
+}
+
+impl DiskReader {
+    /// Opens the files of `t` at [`Torrent::output_paths`]. Missing files
+    /// are not an error; nothing can be read from them.
+    pub(crate) async fn open(t: &Torrent, output: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut start = 0;
+        for (file, path) in t.files().into_iter().zip(t.output_paths(output)?) {
+            let out = match tokio::fs::File::open(&path).await {
+                Ok(out) => Some(out),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).with_context(|| format!("open {}", path.display())),
+            };
+            files.push((start, file.length, out));
+            start += file.length;
+        }
+        Ok(Self { files })
+    }
+
+    /// Fills `buf` with the data starting at `offset` in the torrent's data.
+    /// Returns false if some of it is in a missing file or past the end of a
+    /// short one.
+    pub(crate) async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<bool> {
+        let end = offset + buf.len();
+        for (start, length, file) in &mut self.files {
+            let (from, to) = (offset.max(*start), end.min(*start + *length));
+            if from >= to {
+                continue;
+            }
+            let Some(file) = file else {
+                return Ok(false);
+            };
+            file.seek(std::io::SeekFrom::Start((from - *start) as u64))
+                .await
+                .context("seek to piece offset")?;
+            match file.read_exact(&mut buf[from - offset..to - offset]).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e).context("read piece"),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[tokio::test]
+async fn pieces_spanning_files_are_split() {
+    let output = crate::mock::temp_path("disk-writer");
+    let mut t = crate::mock::torrent(4, b"0123456789");
+    t.info.keys = crate::torrent::Keys::MultiFile {
+        files: vec![
+            crate::torrent::File {
+                length: 3,
+                path: vec![String::from("a")],
+            },
+            crate::torrent::File {
+                length: 7,
+                path: vec![String::from("sub"), String::from("b")],
+            },
+        ],
+    };
+
+    let mut disk = DiskWriter::create(&t, &output, &DownloadConfig::default())
+        .await
+        .unwrap();
+    disk.write(8, b"89").await.unwrap();
+    disk.write(0, b"0123").await.unwrap();
+    disk.write(4, b"4567").await.unwrap();
+    disk.flush().await.unwrap();
+    drop(disk);
+
+    assert_eq!(std::fs::read(output.join("a")).unwrap(), b"012");
+    assert_eq!(
+        std::fs::read(output.join("sub").join("b")).unwrap(),
+        b"3456789"
+    );
+
+    let mut reader = DiskReader::open(&t, &output).await.unwrap();
+    let mut buf = [0u8; 4];
+    assert!(reader.read(0, &mut buf).await.unwrap());
+    assert_eq!(&buf, b"0123");
+    std::fs::remove_file(output.join("sub").join("b")).unwrap();
+    let mut reader = DiskReader::open(&t, &output).await.unwrap();
+    assert!(reader.read(0, &mut buf[..3]).await.unwrap(), "only in a");
+    assert!(!reader.read(0, &mut buf).await.unwrap(), "b is missing");
+    std::fs::remove_dir_all(output).unwrap();
+}
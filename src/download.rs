@@ -1,139 +1,2509 @@
-use std::collections::BinaryHeap;
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
-use futures_util::StreamExt;
+use futures_util::{StreamExt, stream::FuturesUnordered};
 use sha1::{Digest, Sha1};
 
 use crate::{
     BLOCK_MAX_SIZE,
-    peer::Peer,
+    peer::{BitField, Endgame, Message, Peer, PeerSnapshot},
     piece::Piece,
+    rate::RateLimiter,
+    socks::Socks5Config,
+    status::DownloadStatus,
     torrent::{File, Torrent},
-    tracker::TrackerResponse,
+    tracker::{AnnounceEvent, AnnounceSchedule, TrackerClient},
 };
 
-pub(crate) async fn download_all(t: Torrent) -> Result<Downloaded> {
-    let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(&t, info_hash)
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Local address outgoing peer connections are bound to.
+    pub bind_addr: Option<SocketAddr>,
+    /// Resume file recording verified pieces. Pieces it lists are skipped, so
+    /// this only applies to the streaming download, where the caller keeps the
+    /// data.
+    pub resume_file: Option<PathBuf>,
+    /// Bound on the whole download; once it passes the download is cancelled
+    /// with [`DeadlineExceeded`].
+    pub deadline: Option<Duration>,
+    /// How many peers to connect to up front. More are connected, in batches
+    /// of this size, only when no connected peer has a piece we still need.
+    pub peer_pool: usize,
+    /// Pauses and resumes the download; keep a clone to control it.
+    pub handle: DownloadHandle,
+    /// Announce `stopped` to the tracker on pause and `started` on resume.
+    pub announce_pause: bool,
+    /// Port announced to trackers as the one we accept peers on.
+    pub listen_port: u16,
+    /// How long a peer may take to answer the handshake, to send its
+    /// BitField, or to answer any of our outstanding requests before it is
+    /// treated as dead.
+    pub peer_timeout: Duration,
+    /// How long a peer may keep us choked after we declare interest before
+    /// it stops being handed blocks.
+    pub unchoke_timeout: Duration,
+    /// SOCKS5 proxy that peer and tracker connections are tunnelled through.
+    pub proxy: Option<Socks5Config>,
+    /// Largest torrent [`Torrent::download_all`] will buffer in memory.
+    pub max_in_memory: usize,
+    /// JSON file rewritten every second with a [`DownloadStatus`].
+    pub status_file: Option<PathBuf>,
+    /// How many peer handshakes may be in progress at once. Downloading
+    /// starts as soon as the first peer is connected.
+    pub initial_connect_concurrency: usize,
+    /// Cap on new peer connection attempts per second, on top of
+    /// `initial_connect_concurrency`, so connecting to a long peer list does
+    /// not trip a router's connection rate limit.
+    pub max_connects_per_sec: Option<u64>,
+    /// Unix permission bits for created output files, regardless of the
+    /// umask. Ignored on other platforms.
+    pub file_mode: Option<u32>,
+    /// How long a piece may go without receiving a block before its
+    /// remaining blocks are taken from the peers working it and handed to
+    /// others. Time spent paused does not count.
+    pub piece_stall_timeout: Duration,
+    /// Most peers one block is requested from at once during the endgame.
+    /// Once every block is requested, idle peers ask for the blocks still
+    /// missing too, up to this many peers per block.
+    pub endgame_max_duplicates: usize,
+    /// Once fewer blocks than this are left in the whole torrent, the rest
+    /// are requested from several peers, see `endgame_max_duplicates`, and
+    /// cancelled at the others as soon as one delivers. Before that, each
+    /// block is requested from one peer only.
+    pub endgame_blocks: usize,
+    /// How many times a piece is attempted, when blocks go missing or it
+    /// fails its hash check, before the download gives up.
+    pub max_piece_attempts: usize,
+    /// Ban a peer that alone supplied a piece failing its hash check. Peers
+    /// that only contributed some of its blocks are logged either way.
+    pub ban_corrupt_peers: bool,
+    /// Pieces already in the output, e.g. as found by [`verify_existing`].
+    /// They are skipped, and [`download_to_disk`] keeps the output files
+    /// instead of truncating them. In-memory downloads refuse it, as they
+    /// would return those pieces as zeros.
+    pub have: Option<BitField>,
+    /// Order in which pieces are downloaded.
+    pub strategy: PieceStrategy,
+    /// Cap on incoming piece data, in bytes per second.
+    pub max_download_rate: Option<u64>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            resume_file: None,
+            deadline: None,
+            peer_pool: 5,
+            handle: DownloadHandle::default(),
+            announce_pause: false,
+            listen_port: 6881,
+            peer_timeout: Duration::from_secs(30),
+            unchoke_timeout: Duration::from_secs(30),
+            proxy: None,
+            max_in_memory: 1 << 30,
+            status_file: None,
+            initial_connect_concurrency: 5,
+            max_connects_per_sec: None,
+            file_mode: None,
+            piece_stall_timeout: Duration::from_secs(60),
+            endgame_max_duplicates: 2,
+            endgame_blocks: 20,
+            max_piece_attempts: 5,
+            ban_corrupt_peers: true,
+            have: None,
+            strategy: PieceStrategy::default(),
+            max_download_rate: None,
+        }
+    }
+}
+
+/// Which piece [`DownloadConfig`] downloads next. Pieces asked for with
+/// [`DownloadHandle::prioritize_byte_range`] go first either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceStrategy {
+    /// Pieces the fewest connected peers have first, so the swarm does not
+    /// lose them when those peers leave.
+    #[default]
+    RarestFirst,
+    /// Pieces in order, for using the data while it downloads.
+    Sequential,
+}
+
+/// Builds a download of a torrent, with defaults for every option not set.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    torrent: Torrent,
+    config: DownloadConfig,
+    peers: Option<Vec<SocketAddr>>,
+}
+
+impl Downloader {
+    pub fn new(torrent: Torrent) -> Self {
+        Self {
+            torrent,
+            config: DownloadConfig::default(),
+            peers: None,
+        }
+    }
+
+    /// Replaces every option with `config`.
+    pub fn config(mut self, config: DownloadConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: PieceStrategy) -> Self {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// How many peers to connect to at a time, see
+    /// [`DownloadConfig::peer_pool`].
+    pub fn max_peers(mut self, peers: usize) -> Self {
+        self.config.peer_pool = peers;
+        self
+    }
+
+    /// Caps the download at `bytes_per_second`.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.max_download_rate = Some(bytes_per_second);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Socks5Config) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.bind_addr = Some(addr);
+        self
+    }
+
+    pub fn status_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.status_file = Some(path.into());
+        self
+    }
+
+    /// Downloads from `peers` instead of asking the tracker for them.
+    pub fn peers(mut self, peers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.peers = Some(peers.into_iter().collect());
+        self
+    }
+
+    pub fn build(self) -> Download {
+        Download(self)
+    }
+}
+
+/// A download ready to run, see [`Downloader`].
+#[derive(Debug, Clone)]
+pub struct Download(Downloader);
+
+impl Download {
+    /// Controls the download once it runs.
+    pub fn handle(&self) -> DownloadHandle {
+        self.0.config.handle.clone()
+    }
+
+    pub async fn run(self) -> Result<Downloaded> {
+        let Downloader {
+            torrent: t,
+            config,
+            peers,
+        } = self.0;
+        match peers {
+            Some(peers) => {
+                download_in_memory(&t, &config, |sink| {
+                    download_from_peers(&t, &peers, &config, sink)
+                })
+                .await
+            }
+            None => download_all(t, &config).await,
+        }
+    }
+}
+
+/// Controls a running download. Pausing stops new requests while peers stay
+/// connected; requests already sent are still collected.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadHandle {
+    paused: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Byte ranges asked for since the scheduler last looked.
+    prioritized: Arc<Mutex<Vec<Range<u64>>>>,
+    peers: Arc<Mutex<Vec<PeerSnapshot>>>,
+    buffers: Arc<Mutex<BufferPool>>,
+}
+
+impl DownloadHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub(crate) fn subscribe(&self) -> tokio::sync::watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    /// Downloads the pieces holding bytes `start..end` next, for example
+    /// where a player seeks to. Later calls go ahead of earlier ones.
+    pub fn prioritize_byte_range(&self, start: u64, end: u64) {
+        self.prioritized
+            .lock()
+            .expect("prioritized ranges poisoned")
+            .push(start..end);
+    }
+
+    fn take_prioritized(&self) -> Vec<Range<u64>> {
+        std::mem::take(
+            &mut *self
+                .prioritized
+                .lock()
+                .expect("prioritized ranges poisoned"),
+        )
+    }
+
+    /// The peers the download is connected to, as of when it last looked at
+    /// them: on connecting and around every piece.
+    pub fn peers(&self) -> Vec<PeerSnapshot> {
+        self.peers.lock().expect("peer snapshots poisoned").clone()
+    }
+
+    fn publish_peers(&self, peers: &[Peer]) {
+        *self.peers.lock().expect("peer snapshots poisoned") = peers
+            .iter()
+            .filter(|peer| peer.is_connected())
+            .map(Peer::snapshot)
+            .collect();
+    }
+
+    /// Hands back the bytes of a piece once they are written out, so a later
+    /// piece reuses the allocation.
+    pub(crate) fn recycle(&self, buffer: Vec<u8>) {
+        let mut pool = self.buffers.lock().expect("buffer pool poisoned");
+        if pool.free.len() < BufferPool::MAX_FREE {
+            pool.free.push(buffer);
+        }
+    }
+
+    /// A zeroed buffer of `len` bytes, reusing a recycled one if there is
+    /// one. Fresh buffers get room for `capacity` bytes, a whole piece, so
+    /// they can be reused for any piece later.
+    fn piece_buffer(&self, len: usize, capacity: usize) -> Vec<u8> {
+        let mut pool = self.buffers.lock().expect("buffer pool poisoned");
+        let mut buffer = pool.free.pop().unwrap_or_else(|| {
+            pool.allocated += 1;
+            Vec::with_capacity(capacity)
+        });
+        // Only the length shrinks for the short last piece; the capacity
+        // stays for the next one.
+        buffer.clear();
+        buffer.resize(len, 0);
+        buffer
+    }
+}
+
+/// Piece buffers waiting to be reused, see [`DownloadHandle::recycle`].
+#[derive(Debug, Default)]
+struct BufferPool {
+    free: Vec<Vec<u8>>,
+    /// Buffers allocated because none was free.
+    allocated: usize,
+}
+
+impl BufferPool {
+    /// Most buffers kept around; as many as the in-memory download has
+    /// pieces in flight.
+    const MAX_FREE: usize = 4;
+}
+
+/// The download ran past [`DownloadConfig::deadline`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    /// Indices of the pieces that were complete when the deadline passed.
+    pub completed: Vec<usize>,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "download deadline exceeded with {} pieces complete",
+            self.completed.len()
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// A piece's data did not match its SHA-1 in the torrent, so at least one
+/// peer sent a corrupt block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMismatch {
+    pub index: usize,
+}
+
+impl std::fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "piece {} does not match its hash", self.index)
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+pub(crate) async fn download_all(t: Torrent, config: &DownloadConfig) -> Result<Downloaded> {
+    download_in_memory(&t, config, |sink| download_streaming(&t, config, sink)).await
+}
+
+/// Downloads every piece from `peer` alone, ignoring the tracker and the rest
+/// of the swarm.
+pub(crate) async fn download_all_single_peer(
+    t: Torrent,
+    peer: SocketAddr,
+    config: &DownloadConfig,
+) -> Result<Downloaded> {
+    let t = &t;
+    download_in_memory(t, config, |sink| async move {
+        download_from_peers(t, &[peer], config, sink)
+            .await
+            .with_context(|| format!("download from {peer}"))
+    })
+    .await
+}
+
+/// Checks which pieces of `t` the files at `output` (see
+/// [`Torrent::output_paths`]) already hold, e.g. after an interrupted
+/// [`download_to_disk`]. Missing and short files count as holding none of
+/// the pieces they are missing.
+pub async fn verify_existing(t: &Torrent, output: &std::path::Path) -> Result<BitField> {
+    let verified = crate::verify::verify_files(t, output).await?;
+    let mut have = BitField::empty(verified.len());
+    for (piece_i, _) in verified.iter().enumerate().filter(|(_, ok)| **ok) {
+        have.set_piece(piece_i as u32);
+    }
+    Ok(have)
+}
+
+/// Downloads `t` straight to disk: a single-file torrent to the file
+/// `output`, a multi-file one to its files below the directory `output`.
+/// Pieces are written at their offsets as they verify, so only the pieces in
+/// flight are held in memory, however large the torrent.
+pub async fn download_to_disk(
+    t: &Torrent,
+    output: &std::path::Path,
+    config: &DownloadConfig,
+) -> Result<()> {
+    write_to_disk(t, output, config, |sink| {
+        download_streaming(t, config, sink)
+    })
+    .await
+}
+
+async fn write_to_disk<F, Fut>(
+    t: &Torrent,
+    output: &std::path::Path,
+    config: &DownloadConfig,
+    download: F,
+) -> Result<()>
+where
+    F: FnOnce(tokio::sync::mpsc::Sender<(usize, Vec<u8>)>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut disk = crate::disk::DiskWriter::create(t, output, config).await?;
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(4);
+    let download = download(sink);
+    let write = async {
+        while let Some((piece_i, bytes)) = pieces.recv().await {
+            disk.write(piece_i * t.info.piece_length, &bytes)
+                .await
+                .with_context(|| format!("write piece {piece_i}"))?;
+            config.handle.recycle(bytes);
+        }
+        disk.flush().await
+    };
+    let (downloaded, written) = tokio::join!(download, write);
+    // A failed write drops the receiver, which is what stops the download.
+    written?;
+    downloaded
+}
+
+async fn download_in_memory<F, Fut>(
+    t: &Torrent,
+    config: &DownloadConfig,
+    download: F,
+) -> Result<Downloaded>
+where
+    F: FnOnce(tokio::sync::mpsc::Sender<(usize, Vec<u8>)>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    t.validate().context("invalid torrent")?;
+    anyhow::ensure!(
+        config.resume_file.is_none(),
+        "resuming needs the streaming download, the in-memory buffer starts empty"
+    );
+    anyhow::ensure!(
+        config.have.is_none(),
+        "pieces already on disk only apply to a download to disk, the in-memory buffer starts empty"
+    );
+    anyhow::ensure!(
+        t.length() <= config.max_in_memory,
+        "torrent is {} bytes, more than the {} bytes allowed in memory; use the streaming download instead",
+        t.length(),
+        config.max_in_memory
+    );
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(4);
+    let mut all_pieces = vec![0u8; t.length()];
+
+    let download = download(sink);
+    let collect = async {
+        while let Some((piece_i, bytes)) = pieces.recv().await {
+            // The last piece is usually shorter than `piece_length`.
+            let start = piece_i * t.info.piece_length;
+            all_pieces[start..start + bytes.len()].copy_from_slice(&bytes);
+            config.handle.recycle(bytes);
+        }
+    };
+    let (downloaded, ()) = tokio::join!(download, collect);
+    downloaded?;
+
+    Ok(Downloaded {
+        bytes: all_pieces,
+        files: t.files(),
+    })
+}
+
+/// Creates (or truncates) the output file at `path`, with
+/// [`DownloadConfig::file_mode`] permissions if set.
+pub async fn create_output(
+    path: &std::path::Path,
+    config: &DownloadConfig,
+) -> Result<tokio::fs::File> {
+    open_output(path, config, true).await
+}
+
+/// Like [`create_output`], but keeps what an existing file holds unless
+/// `truncate` is set.
+pub(crate) async fn open_output(
+    path: &std::path::Path,
+    config: &DownloadConfig,
+    truncate: bool,
+) -> Result<tokio::fs::File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(truncate);
+    #[cfg(unix)]
+    if let Some(mode) = config.file_mode {
+        options.mode(mode);
+    }
+    let file = options
+        .open(path)
+        .await
+        .with_context(|| format!("create {}", path.display()))?;
+    #[cfg(unix)]
+    if let Some(mode) = config.file_mode {
+        // `mode` only applies to new files, and is narrowed by the umask.
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await
+            .with_context(|| format!("set permissions of {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = config.file_mode;
+    Ok(file)
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn output_file_gets_requested_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = crate::mock::temp_path("mode.bin");
+    let config = DownloadConfig {
+        file_mode: Some(0o664),
+        ..Default::default()
+    };
+    drop(create_output(&path, &config).await.unwrap());
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(mode & 0o777, 0o664);
+}
+
+/// Downloads every piece, sending `(piece_index, bytes)` to `sink` as soon as
+/// each one verifies. The bounded channel applies backpressure to the download.
+pub async fn download_all_streaming(
+    t: Torrent,
+    sink: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+) -> Result<()> {
+    download_streaming(&t, &DownloadConfig::default(), sink).await
+}
+
+pub(crate) async fn download_streaming(
+    t: &Torrent,
+    config: &DownloadConfig,
+    sink: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+) -> Result<()> {
+    let mut tracker = tracker_client(config)?;
+    let peer_info = tracker
+        .query(t)
         .await
         .context("query tracker for peer info")?;
 
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => peer_list.push(peer),
-            Err(e) => eprint!("failed to connect to peer {peer_addr:?}: {e:?}"),
+    let _announcer = if config.announce_pause {
+        let (t, mut paused) = (t.clone(), config.handle.subscribe());
+        let (mut client, port) = (tracker_client(config)?, config.listen_port);
+        let mut schedule = AnnounceSchedule::new(Some(&peer_info));
+        Some(tokio_util::task::AbortOnDropHandle::new(tokio::spawn(
+            async move {
+                let mut announced_paused = false;
+                while paused.changed().await.is_ok() {
+                    // Toggling faster than `min interval` must not make us
+                    // announce faster; only the state we end up in is sent.
+                    schedule.announce_early();
+                    schedule.wait().await;
+                    let now_paused = *paused.borrow_and_update();
+                    if now_paused == announced_paused {
+                        continue;
+                    }
+                    let event = if now_paused {
+                        AnnounceEvent::Stopped
+                    } else {
+                        AnnounceEvent::Started
+                    };
+                    match client.query_event(&t, port, t.length(), Some(event)).await {
+                        Ok(response) => {
+                            announced_paused = now_paused;
+                            schedule.announced(Some(&response));
+                        }
+                        Err(e) => {
+                            eprintln!("announce failed: {e:?}");
+                            schedule.announced(None);
+                        }
+                    }
+                }
+            },
+        )))
+    } else {
+        None
+    };
+
+    let reannounce = Reannounce {
+        schedule: AnnounceSchedule::new(Some(&peer_info)),
+        client: tracker,
+    };
+    download_with_tracker(t, &peer_info.all_peers(), Some(reannounce), config, sink).await
+}
+
+/// The tracker a download got its peers from, asked for more once every
+/// one of them has been tried.
+struct Reannounce {
+    client: TrackerClient,
+    schedule: AnnounceSchedule,
+}
+
+impl Reannounce {
+    /// Announces again ahead of the interval, but not before the tracker's
+    /// `min interval` since the last announce is up.
+    async fn more_peers(&mut self, t: &Torrent) -> Result<Vec<SocketAddr>> {
+        self.schedule.announce_early();
+        self.schedule.wait().await;
+        let response = self.client.query(t).await;
+        self.schedule.announced(response.as_ref().ok());
+        Ok(response?.all_peers())
+    }
+}
+
+/// A tracker client that honours [`DownloadConfig::proxy`].
+fn tracker_client(config: &DownloadConfig) -> Result<TrackerClient> {
+    match &config.proxy {
+        Some(proxy) => TrackerClient::with_proxy(proxy),
+        None => Ok(TrackerClient::new()),
+    }
+}
+
+pub(crate) async fn download_from_peers(
+    t: &Torrent,
+    peer_addrs: &[SocketAddr],
+    config: &DownloadConfig,
+    sink: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+) -> Result<()> {
+    download_with_tracker(t, peer_addrs, None, config, sink).await
+}
+
+/// Like [`download_from_peers`], but asks `tracker` for more peers when
+/// none of `peer_addrs` has a piece still needed.
+async fn download_with_tracker(
+    t: &Torrent,
+    peer_addrs: &[SocketAddr],
+    tracker: Option<Reannounce>,
+    config: &DownloadConfig,
+    sink: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+) -> Result<()> {
+    let mut completed = Vec::new();
+    let download = download_pieces(t, peer_addrs, tracker, config, sink, &mut completed);
+    let Some(deadline) = config.deadline else {
+        return download.await;
+    };
+    match tokio::time::timeout(deadline, download).await {
+        Ok(downloaded) => downloaded,
+        Err(_) => {
+            completed.sort_unstable();
+            Err(DeadlineExceeded { completed }.into())
         }
     }
-    drop(peers);
+}
+
+async fn download_pieces(
+    t: &Torrent,
+    peer_addrs: &[SocketAddr],
+    mut tracker: Option<Reannounce>,
+    config: &DownloadConfig,
+    sink: tokio::sync::mpsc::Sender<(usize, Vec<u8>)>,
+    completed: &mut Vec<usize>,
+) -> Result<()> {
+    t.validate().context("invalid torrent")?;
+    let mut resume = config
+        .resume_file
+        .as_deref()
+        .map(|path| crate::resume::load_or_new(path, t))
+        .transpose()?;
 
-    let mut peers = peer_list;
+    let mut connector = Connector::new(t, peer_addrs, config);
+    let mut peers = Vec::new();
+    connector.wait_connected(&mut peers).await;
+    config.handle.publish_peers(&peers);
 
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
+    let started = std::time::Instant::now();
+    let mut status = DownloadStatus {
+        pieces_total: t.info.pieces.0.len(),
+        connected_peers: peers.iter().filter(|peer| peer.is_connected()).count(),
+        ..Default::default()
+    };
+    let (status_tx, status_rx) = tokio::sync::watch::channel(status.clone());
+    let status_writer = config.status_file.clone().map(|path| {
+        tokio_util::task::AbortOnDropHandle::new(tokio::spawn(crate::status::keep_written(
+            path, status_rx,
+        )))
+    });
 
+    let mut need_pieces = NeedPieces::new(config.strategy);
     for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
+        if resume.as_ref().is_some_and(|r| r.has_piece(piece_i))
+            || config
+                .have
+                .as_ref()
+                .is_some_and(|have| have.has_piece(piece_i as u32))
+        {
+            completed.push(piece_i);
+            continue;
+        }
+        need_pieces.push(Piece::new(piece_i, t, &peers));
+    }
+
+    let mut prioritized = VecDeque::new();
+    let mut download_limit = config
+        .max_download_rate
+        .map(|rate| RateLimiter::new(rate, BLOCK_MAX_SIZE as u64));
+    // Failed attempts per piece, and the peers that worked on them.
+    let mut failed: HashMap<usize, (usize, HashSet<usize>)> = HashMap::new();
+    // Peers `need_pieces` was ranked with, see `Piece`'s ordering.
+    let mut ranked_with = peers.len();
+    loop {
+        // Availability changes as peers join; rank what is left again.
+        connector.connected(&mut peers);
+        if peers.len() != ranked_with {
+            ranked_with = peers.len();
+            need_pieces.rerank(t, &peers);
+        }
+        for range in config.handle.take_prioritized() {
+            for piece_i in t.pieces_overlapping(range.start, range.end).rev() {
+                prioritized.push_front(piece_i);
+            }
+        }
+        let Some(piece) =
+            pop_prioritized(&mut need_pieces, &mut prioritized).or_else(|| need_pieces.pop())
+        else {
+            break;
+        };
+        // Peers may have joined since the piece was queued.
+        connector.connected(&mut peers);
+        // Peers without work for a while would otherwise drop us.
+        for peer in &mut peers {
+            if let Err(e) = peer.keep_alive().await {
+                eprintln!("Failed to send keep-alive: {e:#}");
+            }
+        }
+        let mut piece = Piece::new(piece.index() as usize, t, &peers);
+        while piece.peers().is_empty() {
+            if !connector.wait_connected(&mut peers).await {
+                let Some(tracker) = &mut tracker else {
+                    anyhow::bail!("no peer has piece {}", piece.index());
+                };
+                eprintln!(
+                    "no peer has piece {}, asking the tracker for more",
+                    piece.index()
+                );
+                let more = match tracker.more_peers(t).await {
+                    Ok(more) => more,
+                    Err(e) => {
+                        eprintln!("announce failed: {e:#}");
+                        Vec::new()
+                    }
+                };
+                anyhow::ensure!(
+                    connector.add_addrs(more),
+                    "no peer has piece {}, and the tracker knows no others",
+                    piece.index()
+                );
+            }
+            piece = Piece::new(piece.index() as usize, t, &peers);
+        }
+        config.handle.publish_peers(&peers);
+        let piece_size = piece.length();
+        let blocks_num = piece_size.div_ceil(BLOCK_MAX_SIZE);
+        let mut buffer = PieceBuffer::reusing(
+            config
+                .handle
+                .piece_buffer(piece_size as usize, t.info.piece_length),
+        );
+        // Peers that let this piece stall, which only get it back through a
+        // fresh attempt at the whole piece.
+        let mut stalled = HashSet::new();
+        // Every peer handed blocks of this attempt.
+        let mut tried = HashSet::new();
+
+        loop {
+            // Peers an earlier attempt failed with sit out while others can
+            // take the piece.
+            let avoid = failed
+                .get(&(piece.index() as usize))
+                .map(|(_, peers)| peers)
+                .filter(|avoid| {
+                    piece
+                        .peers()
+                        .iter()
+                        .any(|peer_i| !avoid.contains(peer_i) && !stalled.contains(peer_i))
+                });
+            // The preferred peer joins first so it is handed the first block.
+            let preferred = piece.preferred_peer(peers.iter().map(Peer::stats));
+            let mut joining: Vec<_> = peers
+                .iter_mut()
+                .enumerate()
+                .filter(|(peer_i, _)| {
+                    piece.peers().contains(peer_i)
+                        && !stalled.contains(peer_i)
+                        && !avoid.is_some_and(|avoid| avoid.contains(peer_i))
+                })
+                .collect();
+            // Peers that never unchoked us only get work when nobody else can.
+            if joining.iter().any(|(_, peer)| !peer.is_snubbed()) {
+                joining.retain(|(_, peer)| !peer.is_snubbed());
+            }
+            joining.sort_by_key(|(peer_i, _)| Some(*peer_i) != preferred);
+            let working: Vec<usize> = joining.iter().map(|(peer_i, _)| *peer_i).collect();
+            tried.extend(working.iter().copied());
+
+            let missing = buffer.missing_blocks();
+            let missing_blocks = missing.len();
+            let (submit, tasks) = kanal::bounded_async(missing.len());
+            for block in missing {
+                submit.send(block).await.expect("send block index to tasks");
+            }
+            let (finish, mut done) = tokio::sync::mpsc::channel(blocks_num as usize);
+            let queued_blocks: usize = need_pieces
+                .iter()
+                .map(|p: &Piece| p.length().div_ceil(BLOCK_MAX_SIZE) as usize)
+                .sum();
+            let endgame = if queued_blocks + missing_blocks < config.endgame_blocks {
+                Endgame::new(config.endgame_max_duplicates.min(working.len()))
+            } else {
+                Endgame::new(1)
+            };
+            let mut participates = FuturesUnordered::new();
+            for (_, peer) in joining {
+                participates.push(peer.participate(
+                    piece.index(),
+                    piece_size,
+                    blocks_num,
+                    submit.clone(),
+                    tasks.clone(),
+                    finish.clone(),
+                    endgame.clone(),
+                ));
+            }
+            drop(submit);
+            drop(finish);
+            drop(tasks);
+
+            let collected = collect_piece(
+                &mut buffer,
+                &mut participates,
+                &mut done,
+                config.piece_stall_timeout,
+                &config.handle,
+                download_limit.as_mut(),
+            )
+            .await?;
+            drop(participates);
+            if collected == Collected::Finished {
+                // Endgame duplicates the peers did not get to answer.
+                for &peer_i in &working {
+                    if let Err(e) = peers[peer_i]
+                        .cancel_outstanding(piece.index(), piece_size)
+                        .await
+                    {
+                        eprintln!("Failed to cancel duplicate requests: {e:#}");
+                    }
+                }
+                break;
+            }
+
+            eprintln!(
+                "piece {} stalled, handing its remaining blocks to other peers",
+                piece.index()
+            );
+            for peer_i in working {
+                if let Err(e) = peers[peer_i]
+                    .cancel_outstanding(piece.index(), piece_size)
+                    .await
+                {
+                    eprintln!("Failed to cancel requests to a stalled peer: {e:#}");
+                }
+                stalled.insert(peer_i);
+            }
+            connector.connected(&mut peers);
+            piece = Piece::new(piece.index() as usize, t, &peers);
+            while piece.peers().iter().all(|peer_i| stalled.contains(peer_i)) {
+                if !connector.wait_connected(&mut peers).await {
+                    break;
+                }
+                piece = Piece::new(piece.index() as usize, t, &peers);
+            }
+            if piece.peers().iter().all(|peer_i| stalled.contains(peer_i)) {
+                break;
+            }
+        }
+
+        let piece_i = piece.index() as usize;
+        let all_blocks = if !buffer.is_complete() {
+            Err(anyhow::anyhow!(
+                "some blocks are missing for piece {piece_i}"
+            ))
         } else {
-            need_pieces.push(piece);
+            let contributors = buffer.contributors();
+            match buffer.finish() {
+                (all_blocks, hash) if hash == piece.hash() => Ok(all_blocks),
+                (all_blocks, _) => {
+                    config.handle.recycle(all_blocks);
+                    let mut addrs: Vec<_> = contributors.iter().map(ToString::to_string).collect();
+                    addrs.sort();
+                    eprintln!(
+                        "piece {piece_i} does not match its hash, blocks came from {}",
+                        addrs.join(", ")
+                    );
+                    if let [culprit] = Vec::from_iter(contributors)[..]
+                        && config.ban_corrupt_peers
+                        && let Some(peer) = peers.iter_mut().find(|peer| peer.addr() == culprit)
+                    {
+                        eprintln!("banning {culprit}, which sent all of piece {piece_i}");
+                        peer.ban();
+                    }
+                    Err(HashMismatch { index: piece_i }.into())
+                }
+            }
+        };
+        let all_blocks = match all_blocks {
+            Ok(all_blocks) => all_blocks,
+            Err(reason) => {
+                let (attempts, failed_with) = failed.entry(piece_i).or_default();
+                *attempts += 1;
+                failed_with.extend(tried);
+                if *attempts >= config.max_piece_attempts {
+                    return Err(reason.context(format!("piece {piece_i} failed {attempts} times")));
+                }
+                eprintln!("{reason}, queueing the piece again");
+                // Give another peer the chance to join before the next attempt.
+                connector.wait_connected(&mut peers).await;
+                need_pieces.push(piece);
+                continue;
+            }
+        };
+
+        status.bytes_downloaded += all_blocks.len();
+        sink.send((piece.index() as usize, all_blocks))
+            .await
+            .context("piece receiver dropped")?;
+        completed.push(piece.index() as usize);
+
+        status.pieces_complete = completed.len();
+        status.download_rate = status.bytes_downloaded as f64 / started.elapsed().as_secs_f64();
+        status.connected_peers = peers.iter().filter(|peer| peer.is_connected()).count();
+        status_tx.send_replace(status.clone());
+        config.handle.publish_peers(&peers);
+
+        if let (Some(resume), Some(path)) = (&mut resume, &config.resume_file) {
+            resume.set_piece(piece.index() as usize);
+            crate::resume::save_resume(path, resume)?;
+        }
+
+        for peer in &mut peers {
+            let wanted = need_pieces
+                .iter()
+                .any(|p: &Piece| peer.has_piece(p.index()));
+            if !wanted && let Err(e) = peer.lose_interest().await {
+                eprintln!("Failed to tell peer we are not interested: {e:#}");
+            }
         }
     }
 
-    assert!(no_peers.is_empty(), "pieces with no peers: {no_peers:?}");
+    // The peers disconnect as they are dropped.
+    config.handle.publish_peers(&[]);
+    drop(status_tx);
+    if let Some(writer) = status_writer {
+        writer.await.context("join status writer")?;
+    }
+    Ok(())
+}
 
-    let mut all_pieces = vec![0u8; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        let piece_size = piece.length();
-        let blocks_num = (piece_size + BLOCK_MAX_SIZE - 1) / BLOCK_MAX_SIZE;
+/// Pieces still to download, in the order their [`PieceStrategy`] takes
+/// them.
+enum NeedPieces {
+    RarestFirst(BinaryHeap<Piece>),
+    /// By index, so the next piece in order is found without a scan.
+    Sequential(BTreeMap<usize, Piece>),
+}
 
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
+impl NeedPieces {
+    fn new(strategy: PieceStrategy) -> Self {
+        match strategy {
+            PieceStrategy::RarestFirst => Self::RarestFirst(BinaryHeap::new()),
+            PieceStrategy::Sequential => Self::Sequential(BTreeMap::new()),
+        }
+    }
 
-        let (submit, tasks) = kanal::bounded_async(blocks_num as usize);
-        for block in 0..blocks_num {
-            submit.send(block).await.expect("send block index to tasks");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(blocks_num as usize);
-        let mut participates = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participates.push(peer.participate(
-                piece.index(),
-                piece_size,
-                blocks_num,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        let mut all_blocks = vec![0u8; piece_size as usize];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participates.next() , if !participates.is_empty() => {
-                    match joined {
-                        None => {},
-                        Some(Ok(_)) => {},
-                        Some(Err(e)) => eprintln!("peer task failed: {e:?}"),
+    fn push(&mut self, piece: Piece) {
+        match self {
+            Self::RarestFirst(heap) => heap.push(piece),
+            Self::Sequential(pieces) => {
+                pieces.insert(piece.index() as usize, piece);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Piece> {
+        match self {
+            Self::RarestFirst(heap) => heap.pop(),
+            Self::Sequential(pieces) => pieces.pop_first().map(|(_, piece)| piece),
+        }
+    }
+
+    /// Takes piece `piece_i` out, if it is still needed.
+    fn take(&mut self, piece_i: usize) -> Option<Piece> {
+        match self {
+            Self::RarestFirst(heap) => {
+                let mut found = None;
+                heap.retain(|piece| {
+                    if found.is_none() && piece.index() as usize == piece_i {
+                        found = Some(piece.clone());
+                        return false;
                     }
-                },
-                message = done.recv() => {
-                    if let Some(message) = message {
-                        let piece = crate::peer::Piece::ref_from_bytes(&message.payload[..])
-                            .context("deserialize piece message")?;
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        bytes_received += piece.block().len();
-                        if bytes_received == piece_size as usize {
-                            break;
-                        }
-                    } else {
-                        break;
+                    true
+                });
+                found
+            }
+            Self::Sequential(pieces) => pieces.remove(&piece_i),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Piece> + '_> {
+        match self {
+            Self::RarestFirst(heap) => Box::new(heap.iter()),
+            Self::Sequential(pieces) => Box::new(pieces.values()),
+        }
+    }
+
+    /// Looks up again which of `peers` have each piece, which also ranks the
+    /// pieces again for [`PieceStrategy::RarestFirst`].
+    fn rerank(&mut self, t: &Torrent, peers: &[Peer]) {
+        match self {
+            Self::RarestFirst(heap) => {
+                *heap = std::mem::take(heap)
+                    .into_iter()
+                    .map(|piece| Piece::new(piece.index() as usize, t, peers))
+                    .collect();
+            }
+            Self::Sequential(pieces) => {
+                for (&piece_i, piece) in pieces.iter_mut() {
+                    *piece = Piece::new(piece_i, t, peers);
+                }
+            }
+        }
+    }
+}
+
+/// Takes the first piece of `prioritized` that is still needed out of
+/// `need_pieces`.
+fn pop_prioritized(
+    need_pieces: &mut NeedPieces,
+    prioritized: &mut VecDeque<usize>,
+) -> Option<Piece> {
+    while let Some(piece_i) = prioritized.pop_front() {
+        if let Some(piece) = need_pieces.take(piece_i) {
+            return Some(piece);
+        }
+    }
+    None
+}
+
+/// Connects to peers in the background, so the download starts with the
+/// first peers to answer instead of waiting for every handshake.
+struct Connector {
+    addrs: std::collections::VecDeque<SocketAddr>,
+    /// Every address ever queued, so none is tried twice.
+    known: HashSet<SocketAddr>,
+    connecting: tokio::task::JoinSet<(SocketAddr, Result<Peer>)>,
+    /// Bounds how many handshakes are in progress at once.
+    slots: std::sync::Arc<tokio::sync::Semaphore>,
+    /// Spaces out connection attempts, see
+    /// [`DownloadConfig::max_connects_per_sec`].
+    attempts: Option<Arc<tokio::sync::Mutex<RateLimiter>>>,
+    info_hash: [u8; 20],
+    pieces: usize,
+    config: DownloadConfig,
+}
+
+impl Connector {
+    fn new(t: &Torrent, addrs: &[SocketAddr], config: &DownloadConfig) -> Self {
+        Self {
+            addrs: addrs.iter().copied().collect(),
+            known: addrs.iter().copied().collect(),
+            connecting: tokio::task::JoinSet::new(),
+            slots: std::sync::Arc::new(tokio::sync::Semaphore::new(
+                config.initial_connect_concurrency.max(1),
+            )),
+            attempts: config
+                .max_connects_per_sec
+                .map(|rate| Arc::new(tokio::sync::Mutex::new(RateLimiter::new(rate, 1)))),
+            info_hash: t.info_hash(),
+            pieces: t.info.pieces.0.len(),
+            config: config.clone(),
+        }
+    }
+
+    /// Starts connecting to up to `config.peer_pool` more addresses,
+    /// returning whether any address was left to try.
+    fn connect_more(&mut self) -> bool {
+        let batch_size = self.config.peer_pool.max(1).min(self.addrs.len());
+        let batch: Vec<_> = self.addrs.drain(..batch_size).collect();
+        for peer_addr in &batch {
+            let (peer_addr, slots) = (*peer_addr, self.slots.clone());
+            let (info_hash, pieces, config) = (self.info_hash, self.pieces, self.config.clone());
+            let attempts = self.attempts.clone();
+            self.connecting.spawn(async move {
+                let _slot = slots
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                if let Some(attempts) = &attempts {
+                    attempts.lock().await.acquire(1).await;
+                }
+                let peer = Peer::with_config(peer_addr, info_hash, pieces, &config).await;
+                (peer_addr, peer)
+            });
+        }
+        !batch.is_empty()
+    }
+
+    /// Queues the addresses among `addrs` not seen before, returning whether
+    /// there were any.
+    fn add_addrs(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> bool {
+        let before = self.addrs.len();
+        for addr in addrs {
+            if self.known.insert(addr) {
+                self.addrs.push_back(addr);
+            }
+        }
+        self.addrs.len() > before
+    }
+
+    /// Moves the peers that finished connecting into `peers`.
+    fn connected(&mut self, peers: &mut Vec<Peer>) {
+        while let Some(joined) = self.connecting.try_join_next() {
+            Self::add(joined, peers);
+        }
+    }
+
+    /// Waits for the next connection attempt to finish, starting a new batch
+    /// if none is in progress. Returns `false` once every address was tried.
+    async fn wait_connected(&mut self, peers: &mut Vec<Peer>) -> bool {
+        if self.connecting.is_empty() && !self.connect_more() {
+            return false;
+        }
+        if let Some(joined) = self.connecting.join_next().await {
+            Self::add(joined, peers);
+        }
+        true
+    }
+
+    fn add(
+        joined: Result<(SocketAddr, Result<Peer>), tokio::task::JoinError>,
+        peers: &mut Vec<Peer>,
+    ) {
+        match joined {
+            Ok((_, Ok(peer))) => peers.push(peer),
+            Ok((peer_addr, Err(e))) => eprintln!("failed to connect to peer {peer_addr:?}: {e:?}"),
+            Err(e) => eprintln!("connect task failed: {e}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn running_out_of_peers_announces_after_min_interval() {
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i % 223) as u8).collect();
+    let mut t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let SocketAddr::V4(seeder) = crate::mock::seeder(&t, data.clone()).await else {
+        unreachable!("mock seeders listen on IPv4");
+    };
+    let mut body = b"d8:intervali1800e12:min intervali1e5:peers6:".to_vec();
+    body.extend_from_slice(&seeder.ip().octets());
+    body.extend_from_slice(&seeder.port().to_be_bytes());
+    body.push(b'e');
+    let (tracker, server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], &body)]).await;
+    t.announce = format!("http://{tracker}/announce");
+    // The only peer the first announce gave refuses connections.
+    let refused = [std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap()];
+
+    let first = crate::tracker::TrackerResponse {
+        interval: 1800,
+        min_interval: Some(1),
+        peers: Default::default(),
+        peers6: Default::default(),
+    };
+    let reannounce = Reannounce {
+        client: TrackerClient::new().with_https_upgrade(false),
+        schedule: AnnounceSchedule::new(Some(&first)),
+    };
+    let started = std::time::Instant::now();
+    let config = DownloadConfig::default();
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    let (downloaded, received) = tokio::join!(
+        download_with_tracker(&t, &refused, Some(reannounce), &config, sink),
+        async {
+            let mut received = 0;
+            while pieces.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        }
+    );
+    downloaded.expect("download from the peer the tracker gave later");
+    assert_eq!(received, 2);
+    assert!(
+        started.elapsed() >= Duration::from_secs(1),
+        "announced again after {:?}",
+        started.elapsed()
+    );
+    assert_eq!(server.await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn downloads_before_every_peer_connects() {
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 229) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    // Accepts the connection but never answers the handshake.
+    let (listener, silent) = crate::mock::listen().await;
+    let (accepted, mut was_accepted) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        accepted.send(()).unwrap();
+        std::future::pending::<()>().await;
+        drop(connection);
+    });
+    let peers = [silent, crate::mock::seeder(&t, data.clone()).await];
+
+    let config = DownloadConfig::default();
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    let download = download_from_peers(&t, &peers, &config, sink);
+    let collect = async {
+        let mut received = 0;
+        while pieces.recv().await.is_some() {
+            received += 1;
+        }
+        received
+    };
+    let (downloaded, received) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(download, collect)
+    })
+    .await
+    .expect("download must not wait for the silent peer");
+    downloaded.expect("download from the seeder");
+    assert_eq!(received, 2);
+    assert!(was_accepted.try_recv().is_ok(), "both peers were dialled");
+}
+
+#[tokio::test]
+async fn connect_attempts_are_rate_limited() {
+    let t = crate::mock::torrent(1 << 15, &[0u8; 100]);
+    // Nothing listens there any more, so every attempt is refused at once.
+    let (listener, refusing) = crate::mock::listen().await;
+    drop(listener);
+    let addrs = vec![refusing; 30];
+
+    let config = DownloadConfig {
+        peer_pool: 30,
+        initial_connect_concurrency: 30,
+        max_connects_per_sec: Some(10),
+        ..Default::default()
+    };
+    let mut connector = Connector::new(&t, &addrs, &config);
+    let mut peers = Vec::new();
+    let started = std::time::Instant::now();
+    while connector.wait_connected(&mut peers).await {}
+    assert!(peers.is_empty());
+    // The first attempt goes out right away, the other 29 a tenth apart.
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(2800), "took {elapsed:?}");
+}
+
+#[tokio::test]
+async fn handle_lists_connected_peers() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i / 5) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    // Peer `i` only has piece `i`, so the download needs both.
+    let mut peers = Vec::new();
+    for piece_i in 0..2usize {
+        let (listener, addr) = crate::mock::listen().await;
+        let (info_hash, data) = (t.info_hash(), data.clone());
+        tokio::spawn(async move {
+            let mut stream = crate::mock::accept(&listener, info_hash).await;
+            let message = |tag, payload| Message { tag, payload };
+            stream
+                .send(message(MessageTag::BitField, vec![0x80 >> piece_i]))
+                .await
+                .unwrap();
+            while let Some(Ok(m)) = stream.next().await {
+                match m.tag {
+                    MessageTag::Interested => stream
+                        .send(message(MessageTag::UnChoke, Vec::new()))
+                        .await
+                        .unwrap(),
+                    MessageTag::Request => {
+                        let mut payload = m.payload[..8].to_vec();
+                        payload.extend_from_slice(
+                            &data[piece_i * BLOCK_MAX_SIZE as usize..][..BLOCK_MAX_SIZE as usize],
+                        );
+                        stream
+                            .send(message(MessageTag::Piece, payload))
+                            .await
+                            .unwrap();
                     }
+                    _ => {}
                 }
             }
+        });
+        peers.push(addr);
+    }
+
+    let config = DownloadConfig::default();
+    let handle = config.handle.clone();
+    // Room for one piece only, so the download holds on to its peers until
+    // the snapshot was checked.
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(1);
+    let (downloaded, ()) = tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+        let snapshot = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let snapshot = handle.peers();
+                if snapshot.len() == 2 {
+                    break snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("both peers show up");
+
+        let mut addrs: Vec<_> = snapshot.iter().map(|peer| peer.addr).collect();
+        addrs.sort();
+        let mut expected = peers.clone();
+        expected.sort();
+        assert_eq!(addrs, expected);
+        for peer in &snapshot {
+            assert_eq!((peer.pieces_have, peer.pieces_total), (1, 2), "{peer:?}");
         }
-        drop(participates);
+        let bytes: u64 = snapshot.iter().map(|peer| peer.bytes_downloaded).sum();
+        assert_eq!(bytes, BLOCK_MAX_SIZE as u64, "the first piece is in");
 
-        if bytes_received == piece_size as usize {
-            // All blocks received
-        } else {
-            // Some blocks are missing, re-add the piece to the heap
-            anyhow::bail!("some blocks are missing for piece {}", piece.index());
+        while pieces.recv().await.is_some() {}
+    });
+    downloaded.expect("download from both peers");
+    assert!(
+        handle.peers().is_empty(),
+        "peers are gone after the download"
+    );
+}
+
+#[tokio::test]
+async fn disconnected_peer_is_no_longer_listed() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..3 * BLOCK_MAX_SIZE).map(|i| (i / 3) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+    // Advertises every piece, then hangs up once asked for any.
+    let (listener, quitter) = crate::mock::listen().await;
+    let info_hash = t.info_hash();
+    tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0xe0],
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            if m.tag == MessageTag::Interested {
+                break;
+            }
         }
+    });
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let result: [u8; 20] = hasher.finalize().into();
-        assert_eq!(&result, piece.hash());
+    let path = crate::mock::temp_path("disconnected-status.json");
+    let config = DownloadConfig {
+        status_file: Some(path.clone()),
+        ..Default::default()
+    };
+    let handle = config.handle.clone();
+    // Room for one piece only, so the download waits on the last piece
+    // while the snapshot is checked.
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(1);
+    let peers = [quitter, seeder];
+    let (downloaded, ()) = tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+        pieces.recv().await.expect("first piece");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.peers().len() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the disconnected peer drops out of the list");
+        assert_eq!(handle.peers()[0].addr, seeder);
+        while pieces.recv().await.is_some() {}
+    });
+    downloaded.expect("download from the remaining peer");
 
-        all_pieces[piece.index() as usize * t.info.piece_length..][..piece_size as usize]
-            .copy_from_slice(&all_blocks);
+    let status = crate::status::read_status(&path).expect("status file written");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(status.connected_peers, 1);
+}
+
+#[tokio::test]
+async fn connects_to_peers_lazily() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..3 * BLOCK_MAX_SIZE).map(|i| (i / 7) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let (events, mut log) = tokio::sync::mpsc::unbounded_channel();
+
+    // Peer `i` only has piece `i`.
+    let mut peers = Vec::new();
+    for piece_i in 0..3usize {
+        let (listener, addr) = crate::mock::listen().await;
+        let (info_hash, data, events) = (t.info_hash(), data.clone(), events.clone());
+        tokio::spawn(async move {
+            let mut stream = crate::mock::accept(&listener, info_hash).await;
+            events.send(format!("connect {piece_i}")).unwrap();
+            let message = |tag, payload| Message { tag, payload };
+            stream
+                .send(message(MessageTag::BitField, vec![0x80 >> piece_i]))
+                .await
+                .unwrap();
+            while let Some(Ok(m)) = stream.next().await {
+                match m.tag {
+                    MessageTag::Interested => stream
+                        .send(message(MessageTag::UnChoke, Vec::new()))
+                        .await
+                        .unwrap(),
+                    MessageTag::Request => {
+                        events.send(format!("serve {piece_i}")).unwrap();
+                        let mut payload = m.payload[..8].to_vec();
+                        payload.extend_from_slice(
+                            &data[piece_i * BLOCK_MAX_SIZE as usize..][..BLOCK_MAX_SIZE as usize],
+                        );
+                        stream
+                            .send(message(MessageTag::Piece, payload))
+                            .await
+                            .unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+        peers.push(addr);
     }
+    drop(events);
 
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match t.info.keys {
-            crate::torrent::Keys::SingleFile { length } => vec![File {
-                length,
-                path: vec![t.info.name],
-            }],
-            crate::torrent::Keys::MultiFile { files } => files,
-        },
+    let config = DownloadConfig {
+        peer_pool: 1,
+        ..Default::default()
+    };
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(3);
+    let (downloaded, received) =
+        tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+            let mut received = 0;
+            while pieces.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        });
+    downloaded.expect("download from partial peers");
+    assert_eq!(received, 3);
+
+    let mut order = Vec::new();
+    while let Ok(event) = log.try_recv() {
+        order.push(event);
+    }
+    assert_eq!(order[..2], ["connect 0", "serve 0"], "{order:?}");
+    assert_eq!(order.iter().filter(|e| e.starts_with("connect")).count(), 3);
+}
+
+#[tokio::test]
+async fn paused_download_sends_no_requests() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i / 3) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let requests = std::sync::Arc::new(AtomicUsize::new(0));
+    let (listener, addr) = crate::mock::listen().await;
+    let (info_hash, seen) = (t.info_hash(), requests.clone());
+    let served = data.clone();
+    tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0xc0]))
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            match m.tag {
+                MessageTag::Interested => stream
+                    .send(message(MessageTag::UnChoke, Vec::new()))
+                    .await
+                    .unwrap(),
+                MessageTag::Request => {
+                    seen.fetch_add(1, Ordering::SeqCst);
+                    let piece_i = m.payload[3] as usize;
+                    let mut payload = m.payload[..8].to_vec();
+                    payload.extend_from_slice(
+                        &served[piece_i * BLOCK_MAX_SIZE as usize..][..BLOCK_MAX_SIZE as usize],
+                    );
+                    stream
+                        .send(message(MessageTag::Piece, payload))
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let config = DownloadConfig::default();
+    let handle = config.handle.clone();
+    handle.pause();
+    let peers = [addr];
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    let (downloaded, received) =
+        tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(
+                requests.load(Ordering::SeqCst),
+                0,
+                "paused download sent a request"
+            );
+            handle.resume();
+            let mut received = 0;
+            while pieces.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        });
+    downloaded.expect("download after resume");
+    assert_eq!(received, 2);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn peer_that_never_unchokes_gets_no_blocks() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i / 5) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+
+    let (listener, silent) = crate::mock::listen().await;
+    let info_hash = t.info_hash();
+    let silent_peer = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0xc0],
+            })
+            .await
+            .unwrap();
+        let mut requests = 0;
+        while let Some(Ok(m)) = stream.next().await {
+            requests += (m.tag == MessageTag::Request) as usize;
+        }
+        requests
+    });
+    let responsive = crate::mock::seeder(&t, data.clone()).await;
+
+    let peers = [silent, responsive];
+    let config = DownloadConfig {
+        unchoke_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    let (downloaded, received) =
+        tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+            let mut received = 0;
+            while pieces.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        });
+    downloaded.expect("download from the responsive peer");
+    assert_eq!(received, 2);
+    assert_eq!(silent_peer.await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn oversized_torrent_is_not_buffered() {
+    let t = crate::mock::torrent(1 << 15, &[0u8; 100_000]);
+    let config = DownloadConfig {
+        max_in_memory: 64 * 1024,
+        ..Default::default()
+    };
+    let err = download_all(t, &config)
+        .await
+        .err()
+        .expect("over the limit");
+    assert!(err.to_string().contains("streaming"), "{err}");
+}
+
+#[tokio::test]
+async fn single_peer_downloads_whole_torrent() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 239) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+
+    let downloaded = download_all_single_peer(t, seeder, &DownloadConfig::default())
+        .await
+        .expect("download from the one peer");
+    assert_eq!(downloaded.bytes, data);
+}
+
+#[tokio::test]
+async fn short_final_piece_lands_at_the_end() {
+    // Two full pieces and a final piece of only 5 bytes.
+    let data: Vec<u8> = (0..2 * 1024 + 5u32).map(|i| (i % 241) as u8).collect();
+    let t = crate::mock::torrent(1024, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+
+    let downloaded = download_all_single_peer(t, seeder, &DownloadConfig::default())
+        .await
+        .expect("download a torrent with a short final piece");
+    assert_eq!(downloaded.bytes.len(), data.len());
+    assert_eq!(downloaded.bytes[2048..], data[2048..]);
+    assert_eq!(downloaded.bytes, data);
+}
+
+#[tokio::test]
+async fn stalled_piece_moves_to_fresh_peer() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i % 251) as u8).collect();
+    let t = crate::mock::torrent(data.len(), &data);
+    let (listener, stalling) = crate::mock::listen().await;
+    let (info_hash, served) = (t.info_hash(), data.clone());
+    let (cancels_tx, mut cancels) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+        let mut answered = false;
+        while let Some(Ok(m)) = stream.next().await {
+            match m.tag {
+                MessageTag::Interested => stream
+                    .send(message(MessageTag::UnChoke, Vec::new()))
+                    .await
+                    .unwrap(),
+                // Serve the first block, then go quiet.
+                MessageTag::Request if !answered => {
+                    answered = true;
+                    let begin = u32::from_be_bytes(m.payload[4..8].try_into().unwrap());
+                    let mut payload = m.payload[..8].to_vec();
+                    payload.extend_from_slice(&served[begin as usize..][..BLOCK_MAX_SIZE as usize]);
+                    stream
+                        .send(message(MessageTag::Piece, payload))
+                        .await
+                        .unwrap();
+                }
+                MessageTag::Cancel => cancels_tx.send(m.payload).unwrap(),
+                _ => {}
+            }
+        }
+    });
+    let peers = [stalling, crate::mock::seeder(&t, data.clone()).await];
+
+    let config = DownloadConfig {
+        peer_pool: 1,
+        piece_stall_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let downloaded = tokio::time::timeout(
+        Duration::from_secs(10),
+        download_in_memory(&t, &config, |sink| {
+            download_from_peers(&t, &peers, &config, sink)
+        }),
+    )
+    .await
+    .expect("stalled piece is reassigned before the request timeout")
+    .expect("download with a stalling peer");
+    assert_eq!(downloaded.bytes, data);
+    let cancel = cancels.recv().await.expect("stalled request is cancelled");
+    assert_eq!(cancel.len(), 12);
+}
+
+#[tokio::test]
+async fn piece_is_retried_after_peer_drops() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data: Vec<u8> = (0..4 * BLOCK_MAX_SIZE).map(|i| (i % 247) as u8).collect();
+    let t = crate::mock::torrent(data.len(), &data);
+    // Serves one block of the piece, then hangs up.
+    let (listener, dropping) = crate::mock::listen().await;
+    let (info_hash, served) = (t.info_hash(), data.clone());
+    tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            match m.tag {
+                MessageTag::Interested => stream
+                    .send(message(MessageTag::UnChoke, Vec::new()))
+                    .await
+                    .unwrap(),
+                MessageTag::Request => {
+                    let begin = u32::from_be_bytes(m.payload[4..8].try_into().unwrap());
+                    let mut payload = m.payload[..8].to_vec();
+                    payload.extend_from_slice(&served[begin as usize..][..BLOCK_MAX_SIZE as usize]);
+                    stream
+                        .send(message(MessageTag::Piece, payload))
+                        .await
+                        .unwrap();
+                    break;
+                }
+                _ => {}
+            }
+        }
+    });
+    let peers = [dropping, crate::mock::seeder(&t, data.clone()).await];
+
+    // Only the dropping peer is connected for the first attempt.
+    let config = DownloadConfig {
+        peer_pool: 1,
+        initial_connect_concurrency: 1,
+        ..Default::default()
+    };
+    let downloaded = download_in_memory(&t, &config, |sink| {
+        download_from_peers(&t, &peers, &config, sink)
+    })
+    .await
+    .expect("piece is retried with the other peer");
+    assert_eq!(downloaded.bytes, data);
+}
+
+#[tokio::test]
+async fn corrupt_piece_is_fetched_again_elsewhere() {
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i % 233) as u8).collect();
+    let t = crate::mock::torrent(data.len(), &data);
+    let mut corrupt = data.clone();
+    corrupt[100] ^= 0xff;
+    let peers = [
+        crate::mock::seeder(&t, corrupt).await,
+        crate::mock::seeder(&t, data.clone()).await,
+    ];
+
+    let config = DownloadConfig {
+        peer_pool: 1,
+        initial_connect_concurrency: 1,
+        ..Default::default()
+    };
+    let downloaded = download_in_memory(&t, &config, |sink| {
+        download_from_peers(&t, &peers, &config, sink)
+    })
+    .await
+    .expect("hash mismatch is retried with the other peer");
+    assert_eq!(downloaded.bytes, data);
+
+    let config = DownloadConfig {
+        max_piece_attempts: 2,
+        ban_corrupt_peers: false,
+        ..Default::default()
+    };
+    let err = download_in_memory(&t, &config, |sink| {
+        download_from_peers(&t, &peers[..1], &config, sink)
     })
+    .await
+    .err()
+    .expect("only the corrupt peer is left");
+    assert!(err.to_string().contains("failed 2 times"), "{err}");
+    assert_eq!(
+        err.downcast_ref::<HashMismatch>(),
+        Some(&HashMismatch { index: 0 })
+    );
+}
+
+#[tokio::test]
+async fn peer_sending_a_corrupt_piece_is_banned() {
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i % 229) as u8).collect();
+    let t = crate::mock::torrent(data.len(), &data);
+    let mut corrupt = data.clone();
+    corrupt[BLOCK_MAX_SIZE as usize + 7] ^= 0xff;
+    let peers = [crate::mock::seeder(&t, corrupt).await];
+
+    let config = DownloadConfig::default();
+    let err = download_in_memory(&t, &config, |sink| {
+        download_from_peers(&t, &peers, &config, sink)
+    })
+    .await
+    .err()
+    .expect("the only peer is banned");
+    // Banned after its first corrupt piece, long before the attempts run out.
+    assert!(err.to_string().contains("no peer has piece 0"), "{err}");
+}
+
+#[tokio::test]
+async fn fast_peer_finishes_the_tail() {
+    use crate::seed::{MemoryStore, SeedConfig, Seeder};
+
+    let data: Vec<u8> = (0..16 * BLOCK_MAX_SIZE).map(|i| (i % 223) as u8).collect();
+    let t = crate::mock::torrent(8 * BLOCK_MAX_SIZE as usize, &data);
+    // Sends its first block straight away, then one every 16 seconds.
+    let (listener, slow) = crate::mock::listen().await;
+    let seed_config = SeedConfig {
+        max_upload_rate: Some(1024),
+        ..Default::default()
+    };
+    let store = MemoryStore::new(data.clone(), t.info.piece_length);
+    tokio::spawn(Seeder::new(&t, store, &seed_config).run(listener));
+    let peers = [slow, crate::mock::seeder(&t, data.clone()).await];
+
+    // Blocks are only duplicated in the torrent-wide endgame, so only it
+    // can take blocks off the slow peer.
+    let config = DownloadConfig {
+        endgame_max_duplicates: 2,
+        ..Default::default()
+    };
+    let downloaded = tokio::time::timeout(
+        Duration::from_secs(8),
+        download_in_memory(&t, &config, |sink| {
+            download_from_peers(&t, &peers, &config, sink)
+        }),
+    )
+    .await
+    .expect("the fast peer fetches what the slow one sits on")
+    .expect("download from both peers");
+    assert_eq!(downloaded.bytes, data);
+}
+
+#[tokio::test]
+async fn endgame_duplicates_are_capped() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let data = vec![3u8; 2 * BLOCK_MAX_SIZE as usize];
+    let t = crate::mock::torrent(data.len(), &data);
+    // Peers that have the piece and take requests, but never answer them.
+    let requested: Arc<Mutex<HashMap<u32, usize>>> = Arc::default();
+    let mut peers = Vec::new();
+    for _ in 0..3 {
+        let (listener, addr) = crate::mock::listen().await;
+        let (info_hash, requested) = (t.info_hash(), requested.clone());
+        tokio::spawn(async move {
+            let mut stream = crate::mock::accept(&listener, info_hash).await;
+            let message = |tag, payload| Message { tag, payload };
+            stream
+                .send(message(MessageTag::BitField, vec![0x80]))
+                .await
+                .unwrap();
+            while let Some(Ok(m)) = stream.next().await {
+                match m.tag {
+                    MessageTag::Interested => {
+                        let _ = stream.send(message(MessageTag::UnChoke, Vec::new())).await;
+                    }
+                    MessageTag::Request => {
+                        let begin = u32::from_be_bytes(m.payload[4..8].try_into().unwrap());
+                        *requested.lock().unwrap().entry(begin).or_default() += 1;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        peers.push(addr);
+    }
+
+    // Two blocks are well inside the endgame.
+    let config = DownloadConfig {
+        deadline: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let (sink, _pieces) = tokio::sync::mpsc::channel(1);
+    download_from_peers(&t, &peers, &config, sink)
+        .await
+        .expect_err("nobody answers");
+
+    let requested = requested.lock().unwrap();
+    assert_eq!(requested.len(), 2, "{requested:?}");
+    assert!(
+        requested
+            .values()
+            .all(|&peers| peers == config.endgame_max_duplicates),
+        "each block goes to exactly the capped number of peers: {requested:?}"
+    );
+}
+
+#[tokio::test]
+async fn piece_buffers_are_reused() {
+    // Three pieces, the last one short.
+    let data: Vec<u8> = (0..5 * BLOCK_MAX_SIZE / 2)
+        .map(|i| (i % 227) as u8)
+        .collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+
+    let config = DownloadConfig::default();
+    let downloaded = download_all_single_peer(t, seeder, &config)
+        .await
+        .expect("download from the mock seeder");
+    assert_eq!(downloaded.bytes, data);
+    let pool = config.handle.buffers.lock().unwrap();
+    assert!(pool.allocated < 3, "{} buffers allocated", pool.allocated);
+    assert!(
+        pool.free
+            .iter()
+            .all(|buffer| buffer.capacity() >= BLOCK_MAX_SIZE as usize)
+    );
+}
+
+#[tokio::test]
+async fn downloader_applies_its_options() {
+    let data: Vec<u8> = (0..3 * BLOCK_MAX_SIZE).map(|i| (i % 227) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+
+    let started = std::time::Instant::now();
+    let downloaded = Downloader::new(t)
+        .strategy(PieceStrategy::Sequential)
+        .rate_limit(4 * BLOCK_MAX_SIZE as u64)
+        .peers([seeder])
+        .build()
+        .run()
+        .await
+        .expect("download through the builder");
+    assert_eq!(downloaded.bytes, data);
+    // A block's worth of burst, then a quarter second per block.
+    assert!(
+        started.elapsed() >= Duration::from_millis(450),
+        "{:?}",
+        started.elapsed()
+    );
+}
+
+#[test]
+fn sequential_pieces_come_in_order() {
+    let t = crate::mock::torrent(4, &[0u8; 24]);
+    let mut need_pieces = NeedPieces::new(PieceStrategy::Sequential);
+    for piece_i in [3, 0, 5, 1, 4, 2] {
+        need_pieces.push(Piece::new(piece_i, &t, &[]));
+    }
+    // A prioritized piece is taken out of order, and only once.
+    let mut prioritized = VecDeque::from([4, 4]);
+    let piece = pop_prioritized(&mut need_pieces, &mut prioritized).unwrap();
+    assert_eq!(piece.index(), 4);
+    assert!(pop_prioritized(&mut need_pieces, &mut prioritized).is_none());
+
+    let order: Vec<_> = std::iter::from_fn(|| need_pieces.pop())
+        .map(|piece| piece.index())
+        .collect();
+    assert_eq!(order, [0, 1, 2, 3, 5]);
+}
+
+#[tokio::test]
+async fn prioritized_range_downloads_first() {
+    let data: Vec<u8> = (0..24u8).collect();
+    let t = crate::mock::torrent(4, &data);
+    let seeders = [crate::mock::seeder(&t, data.clone()).await];
+    let config = DownloadConfig::default();
+    // Bytes 5..11 lie in pieces 1 and 2.
+    config.handle.prioritize_byte_range(5, 11);
+
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(6);
+    let (downloaded, order) =
+        tokio::join!(download_from_peers(&t, &seeders, &config, sink), async {
+            let mut order = Vec::new();
+            while let Some((piece_i, _)) = pieces.recv().await {
+                order.push(piece_i);
+            }
+            order
+        });
+    downloaded.expect("download with a prioritized range");
+    assert_eq!(order.len(), 6);
+    assert_eq!(order[..2], [1, 2], "{order:?}");
+}
+
+#[tokio::test]
+async fn status_file_counts_completed_pieces() {
+    let data: Vec<u8> = (0..3000u32).map(|i| (i % 233) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    let seeders = [crate::mock::seeder(&t, data.clone()).await];
+    let path = crate::mock::temp_path("status.json");
+    let config = DownloadConfig {
+        status_file: Some(path.clone()),
+        ..Default::default()
+    };
+
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(1);
+    let download = download_from_peers(&t, &seeders, &config, sink);
+    let (downloaded, _) = tokio::join!(download, async { while pieces.recv().await.is_some() {} });
+    downloaded.expect("download from mock seeder");
+
+    let status = crate::status::read_status(&path).expect("status file written");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(status.pieces_complete, 1);
+    assert_eq!(status.pieces_total, 1);
+    assert_eq!(status.bytes_downloaded, data.len());
+    assert_eq!(status.connected_peers, 1);
+}
+
+#[tokio::test]
+async fn streaming_reassembles_file() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    let seeders = [crate::mock::seeder(&t, data.clone()).await];
+
+    let config = DownloadConfig::default();
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(1);
+    let download = download_from_peers(&t, &seeders, &config, sink);
+    let collect = async {
+        let mut file = vec![0u8; data.len()];
+        let mut received = 0;
+        while let Some((piece_i, bytes)) = pieces.recv().await {
+            file[piece_i * t.info.piece_length..][..bytes.len()].copy_from_slice(&bytes);
+            received += 1;
+        }
+        (file, received)
+    };
+    let (downloaded, (file, received)) = tokio::join!(download, collect);
+    downloaded.expect("download from mock seeder");
+    assert_eq!(received, t.info.pieces.0.len());
+    assert_eq!(file, data);
+}
+
+#[tokio::test]
+async fn one_entry_files_array_downloads() {
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i % 241) as u8).collect();
+    let mut info = crate::mock::torrent(1 << 15, &data).info;
+    info.keys = crate::torrent::Keys::MultiFile {
+        files: vec![File {
+            length: data.len(),
+            path: vec![String::from("mock.bin")],
+        }],
+    };
+    let info = serde_bencode::to_bytes(&info).unwrap();
+    let mut dot_torrent = b"d8:announce25:http://127.0.0.1/announce4:info".to_vec();
+    dot_torrent.extend_from_slice(&info);
+    dot_torrent.push(b'e');
+
+    let t: Torrent = serde_bencode::from_bytes(&dot_torrent).unwrap();
+    assert!(matches!(
+        t.info.keys,
+        crate::torrent::Keys::MultiFile { .. }
+    ));
+    assert_eq!(t.length(), data.len());
+
+    let seeders = [crate::mock::seeder(&t, data.clone()).await];
+    let config = DownloadConfig::default();
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(1);
+    let download = download_from_peers(&t, &seeders, &config, sink);
+    let collect = async {
+        let mut file = vec![0u8; data.len()];
+        while let Some((piece_i, bytes)) = pieces.recv().await {
+            file[piece_i * t.info.piece_length..][..bytes.len()].copy_from_slice(&bytes);
+        }
+        file
+    };
+    let (downloaded, file) = tokio::join!(download, collect);
+    downloaded.expect("download from mock seeder");
+    assert_eq!(file, data);
+}
+
+#[tokio::test]
+async fn multi_file_download_is_written_to_disk() {
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i % 239) as u8).collect();
+    let mut info = crate::mock::torrent(1 << 14, &data).info;
+    // The second file starts and ends inside a piece.
+    info.keys = crate::torrent::Keys::MultiFile {
+        files: vec![
+            File {
+                length: 10_000,
+                path: vec![String::from("a.bin")],
+            },
+            File {
+                length: 20_000,
+                path: vec![String::from("sub"), String::from("b.bin")],
+            },
+            File {
+                length: 10_000,
+                path: vec![String::from("c.bin")],
+            },
+        ],
+    };
+    let info = serde_bencode::to_bytes(&info).unwrap();
+    let mut dot_torrent = b"d8:announce25:http://127.0.0.1/announce4:info".to_vec();
+    dot_torrent.extend_from_slice(&info);
+    dot_torrent.push(b'e');
+    let t: Torrent = serde_bencode::from_bytes(&dot_torrent).unwrap();
+
+    let peers = [crate::mock::seeder(&t, data.clone()).await];
+    let output = crate::mock::temp_path("multi-file");
+    let config = DownloadConfig::default();
+    write_to_disk(&t, &output, &config, |sink| {
+        download_from_peers(&t, &peers, &config, sink)
+    })
+    .await
+    .expect("download to disk");
+
+    let a = std::fs::read(output.join("a.bin")).unwrap();
+    let b = std::fs::read(output.join("sub").join("b.bin")).unwrap();
+    let c = std::fs::read(output.join("c.bin")).unwrap();
+    assert_eq!((a.len(), b.len(), c.len()), (10_000, 20_000, 10_000));
+    assert_eq!([a, b, c].concat(), data);
+    std::fs::remove_dir_all(output).unwrap();
+}
+
+#[tokio::test]
+async fn existing_pieces_are_not_requested_again() {
+    use crate::peer::MessageTag;
+    use futures_util::SinkExt;
+
+    let block = BLOCK_MAX_SIZE as usize;
+    let data: Vec<u8> = (0..4 * block).map(|i| (i % 233) as u8).collect();
+    let t = crate::mock::torrent(block, &data);
+    // Pieces 0 and 2 are already there; 1 is garbage and 3 was never written.
+    let output = crate::mock::temp_path("continue.bin");
+    let mut partial = data[..3 * block].to_vec();
+    partial[block..2 * block].fill(0);
+    std::fs::write(&output, &partial).unwrap();
+
+    let have = verify_existing(&t, &output).await.unwrap();
+    assert_eq!(have.pieces().collect::<Vec<_>>(), [0, 2]);
+
+    let (listener, addr) = crate::mock::listen().await;
+    let info_hash = t.info_hash();
+    let served = data.clone();
+    let peer = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0xf0]))
+            .await
+            .unwrap();
+        let mut requested = Vec::new();
+        while let Some(Ok(m)) = stream.next().await {
+            match m.tag {
+                MessageTag::Interested => stream
+                    .send(message(MessageTag::UnChoke, Vec::new()))
+                    .await
+                    .unwrap(),
+                MessageTag::Request => {
+                    let piece_i = u32::from_be_bytes(m.payload[..4].try_into().unwrap());
+                    requested.push(piece_i as usize);
+                    let mut payload = m.payload[..8].to_vec();
+                    payload.extend_from_slice(&served[piece_i as usize * block..][..block]);
+                    stream
+                        .send(message(MessageTag::Piece, payload))
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+        requested
+    });
+
+    let peers = [addr];
+    let config = DownloadConfig {
+        have: Some(have),
+        ..Default::default()
+    };
+    write_to_disk(&t, &output, &config, |sink| {
+        download_from_peers(&t, &peers, &config, sink)
+    })
+    .await
+    .expect("continue the download");
+
+    let mut requested = peer.await.unwrap();
+    requested.sort_unstable();
+    assert_eq!(requested, [1, 3]);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    std::fs::remove_file(output).unwrap();
+
+    let err = download_all(t, &config)
+        .await
+        .err()
+        .expect("in memory, the pieces we have would be missing");
+    assert!(err.to_string().contains("on disk"), "{err}");
+}
+
+#[tokio::test]
+async fn deadline_reports_completed_pieces() {
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| i as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let (listener, addr) = crate::mock::listen().await;
+    let info_hash = t.info_hash();
+    let served = data.clone();
+    // Serves the first piece asked for, then never answers again.
+    tokio::spawn(async move {
+        use futures_util::SinkExt;
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        use crate::peer::MessageTag;
+        stream
+            .send(message(MessageTag::BitField, vec![0xc0]))
+            .await
+            .unwrap();
+        let mut served_piece = None;
+        while let Some(Ok(m)) = stream.next().await {
+            match m.tag {
+                MessageTag::Interested => stream
+                    .send(message(MessageTag::UnChoke, Vec::new()))
+                    .await
+                    .unwrap(),
+                MessageTag::Request
+                    if *served_piece.get_or_insert(m.payload[3]) == m.payload[3] =>
+                {
+                    let mut payload = m.payload[..8].to_vec();
+                    let start = m.payload[3] as usize * BLOCK_MAX_SIZE as usize;
+                    payload.extend_from_slice(&served[start..][..BLOCK_MAX_SIZE as usize]);
+                    stream
+                        .send(message(MessageTag::Piece, payload))
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let peers = [addr];
+    let config = DownloadConfig {
+        deadline: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    let (downloaded, received) =
+        tokio::join!(download_from_peers(&t, &peers, &config, sink), async {
+            let mut received = Vec::new();
+            while let Some((piece_i, _)) = pieces.recv().await {
+                received.push(piece_i);
+            }
+            received
+        });
+
+    let err = downloaded.expect_err("slow peer must miss the deadline");
+    let exceeded = err
+        .downcast_ref::<DeadlineExceeded>()
+        .expect("deadline error");
+    assert_eq!(exceeded.completed.len(), 1);
+    assert_eq!(received, exceeded.completed);
+}
+
+/// Gathers the blocks of a piece from `done` and returns as soon as all
+/// `piece_size` bytes are in, even if peer tasks are still waiting for work
+/// (and so still hold `finish` senders). Stops early if every sender is gone.
+/// Also returns the piece's SHA-1, fed block by block while blocks arrive in
+/// order. Waiting on `limit` after each block holds off reading more.
+async fn collect_piece<F>(
+    buffer: &mut PieceBuffer,
+    participates: &mut FuturesUnordered<F>,
+    done: &mut tokio::sync::mpsc::Receiver<(SocketAddr, Message)>,
+    stall_timeout: Duration,
+    handle: &DownloadHandle,
+    mut limit: Option<&mut RateLimiter>,
+) -> Result<Collected>
+where
+    F: Future<Output = Result<()>>,
+{
+    let stall = tokio::time::sleep(stall_timeout);
+    tokio::pin!(stall);
+    while !buffer.is_complete() {
+        tokio::select! {
+            joined = participates.next(), if !participates.is_empty() => {
+                if let Some(Err(e)) = joined {
+                    eprintln!("peer task failed: {e:?}");
+                }
+            },
+            message = done.recv() => {
+                let Some((from, message)) = message else {
+                    break;
+                };
+                let piece = crate::peer::Piece::ref_from_bytes(&message.payload[..])
+                    .context("deserialize piece message")?;
+                buffer.block_arrived(from, piece.begin(), piece.block());
+                if let Some(limit) = limit.as_deref_mut() {
+                    limit.acquire(piece.block().len()).await;
+                }
+                stall.as_mut().reset(tokio::time::Instant::now() + stall_timeout);
+            }
+            _ = &mut stall => {
+                if !handle.is_paused() {
+                    return Ok(Collected::Stalled);
+                }
+                stall.as_mut().reset(tokio::time::Instant::now() + stall_timeout);
+            }
+        }
+    }
+    Ok(Collected::Finished)
+}
+
+/// Why [`collect_piece`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Collected {
+    /// The piece is complete, or every peer working it gave up.
+    Finished,
+    /// No block arrived within the stall timeout.
+    Stalled,
+}
+
+/// The blocks of one piece as they arrive, possibly from several rounds of
+/// peers when the first ones stall.
+struct PieceBuffer {
+    data: Vec<u8>,
+    /// Offsets of the blocks received so far, with the peer that sent each.
+    received: HashMap<u32, SocketAddr>,
+    bytes_received: usize,
+    hasher: IncrementalHash,
+}
+
+impl PieceBuffer {
+    /// Collects a piece into `data`, which must be zeroed and exactly as
+    /// long as the piece.
+    fn reusing(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            received: HashMap::new(),
+            bytes_received: 0,
+            hasher: IncrementalHash::default(),
+        }
+    }
+
+    fn block_arrived(&mut self, from: SocketAddr, begin: u32, block: &[u8]) {
+        if self.received.contains_key(&begin) {
+            return;
+        }
+        self.received.insert(begin, from);
+        self.data[begin as usize..][..block.len()].copy_from_slice(block);
+        self.hasher
+            .block_arrived(&self.data, begin as usize, block.len());
+        self.bytes_received += block.len();
+    }
+
+    fn missing_blocks(&self) -> Vec<u32> {
+        (0..(self.data.len() as u32).div_ceil(BLOCK_MAX_SIZE))
+            .filter(|block_i| !self.received.contains_key(&(block_i * BLOCK_MAX_SIZE)))
+            .collect()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.bytes_received == self.data.len()
+    }
+
+    /// The peers that supplied blocks of the piece.
+    fn contributors(&self) -> HashSet<SocketAddr> {
+        self.received.values().copied().collect()
+    }
+
+    fn finish(self) -> (Vec<u8>, [u8; 20]) {
+        let hash = self.hasher.finish(&self.data);
+        (self.data, hash)
+    }
+}
+
+/// Hashes a piece as its blocks arrive. Blocks that land in order are fed to
+/// the hasher straight away; anything after a gap waits until the gap fills or
+/// is hashed from the full buffer in [`IncrementalHash::finish`].
+#[derive(Default)]
+struct IncrementalHash {
+    hasher: Sha1,
+    /// Bytes of the piece already fed to `hasher`.
+    hashed: usize,
+    /// Blocks received past `hashed`, as `begin -> end`.
+    pending: std::collections::BTreeMap<usize, usize>,
+}
+
+impl IncrementalHash {
+    fn block_arrived(&mut self, buf: &[u8], begin: usize, len: usize) {
+        if begin != self.hashed {
+            self.pending.insert(begin, begin + len);
+            return;
+        }
+        let mut end = begin + len;
+        while let Some(next) = self.pending.remove(&end) {
+            end = next;
+        }
+        self.hasher.update(&buf[self.hashed..end]);
+        self.hashed = end;
+    }
+
+    fn finish(mut self, buf: &[u8]) -> [u8; 20] {
+        self.hasher.update(&buf[self.hashed..]);
+        self.hasher.finalize().into()
+    }
+}
+
+#[test]
+fn incremental_hash_matches_full_buffer() {
+    let buf: Vec<u8> = (0..50_000u32).map(|i| (i % 253) as u8).collect();
+    let expected: [u8; 20] = Sha1::digest(&buf).into();
+    let blocks: Vec<_> = (0..buf.len())
+        .step_by(BLOCK_MAX_SIZE as usize)
+        .map(|begin| (begin, (buf.len() - begin).min(BLOCK_MAX_SIZE as usize)))
+        .collect();
+
+    let mut in_order = IncrementalHash::default();
+    for &(begin, len) in &blocks {
+        in_order.block_arrived(&buf, begin, len);
+    }
+    assert_eq!(in_order.hashed, buf.len());
+    assert_eq!(in_order.finish(&buf), expected);
+
+    let mut shuffled = IncrementalHash::default();
+    for &(begin, len) in blocks.iter().rev() {
+        shuffled.block_arrived(&buf, begin, len);
+    }
+    assert_eq!(shuffled.finish(&buf), expected);
+}
+
+#[test]
+fn out_of_order_blocks_land_at_their_offsets() {
+    let block = BLOCK_MAX_SIZE as usize;
+    let piece: Vec<u8> = (0..2 * block + 100).map(|i| (i % 241) as u8).collect();
+    let mut buffer = PieceBuffer::reusing(vec![0u8; piece.len()]);
+    let from = "127.0.0.1:6881".parse().unwrap();
+    for begin in [2 * block, 0, block] {
+        let end = (begin + block).min(piece.len());
+        buffer.block_arrived(from, begin as u32, &piece[begin..end]);
+    }
+    assert!(buffer.is_complete());
+    let (data, hash) = buffer.finish();
+    assert_eq!(data, piece);
+    assert_eq!(hash, <[u8; 20]>::from(Sha1::digest(&piece)));
+}
+
+#[tokio::test]
+async fn collect_stops_when_piece_is_complete() {
+    let (finish, mut done) = tokio::sync::mpsc::channel(2);
+    let mut participates = FuturesUnordered::new();
+    participates.push(async move {
+        for begin in [0u32, 4] {
+            let mut payload = vec![0u8; 4];
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(&[begin as u8 + 1; 4]);
+            let from = SocketAddr::from(([127, 0, 0, 1], 6881 + begin as u16));
+            finish
+                .send((
+                    from,
+                    Message {
+                        tag: crate::peer::MessageTag::Piece,
+                        payload,
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+        // Linger while still holding `finish`, like a peer waiting for work.
+        std::future::pending::<()>().await;
+        Ok(())
+    });
+
+    let mut buffer = PieceBuffer::reusing(vec![0u8; 8]);
+    let collected = tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        collect_piece(
+            &mut buffer,
+            &mut participates,
+            &mut done,
+            Duration::from_secs(60),
+            &DownloadHandle::default(),
+            None,
+        ),
+    )
+    .await
+    .expect("collect returns once the piece is complete")
+    .unwrap();
+    assert_eq!(collected, Collected::Finished);
+    assert_eq!(buffer.bytes_received, 8);
+    assert_eq!(
+        buffer.contributors(),
+        HashSet::from([
+            "127.0.0.1:6881".parse().unwrap(),
+            "127.0.0.1:6885".parse().unwrap()
+        ])
+    );
+    assert_eq!(buffer.finish().0, [1, 1, 1, 1, 5, 5, 5, 5]);
 }
 
 pub struct Downloaded {
@@ -141,6 +2511,13 @@ pub struct Downloaded {
     files: Vec<File>,
 }
 
+impl Downloaded {
+    /// The data of every file back to back, in torrent order.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
 impl<'a> IntoIterator for &'a Downloaded {
     type Item = DownloadFile<'a>;
 
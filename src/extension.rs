@@ -0,0 +1,135 @@
+//! The extension protocol (BEP 10), carried in `Extended` messages once both
+//! sides set the extension bit of the handshake.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::torrent::Info;
+
+/// Reserved handshake bit, in byte 5, advertising the extension protocol.
+pub(crate) const EXTENSION_BIT: u8 = 0x10;
+
+/// Extended message id of the extended handshake itself.
+const HANDSHAKE_ID: u8 = 0;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message ids the sender expects them on.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Size of the info dictionary, for fetching it with `ut_metadata`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    /// Most outstanding requests the sender accepts without dropping some.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Parses the payload of an `Extended` message, which must be a handshake.
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let (&id, dict) = payload.split_first().context("empty extended message")?;
+        anyhow::ensure!(
+            id == HANDSHAKE_ID,
+            "extended message {id} is not a handshake"
+        );
+        serde_bencode::from_bytes(dict).context("deserialize extended handshake")
+    }
+
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        let mut payload = vec![HANDSHAKE_ID];
+        payload.extend(serde_bencode::to_bytes(self).context("serialize extended handshake")?);
+        Ok(payload)
+    }
+
+    /// Whether `payload` of an `Extended` message is a handshake, rather than
+    /// a message of a negotiated extension.
+    pub(crate) fn is_handshake(payload: &[u8]) -> bool {
+        payload.first() == Some(&HANDSHAKE_ID)
+    }
+}
+
+/// Size of the pieces `ut_metadata` (BEP 9) splits the info dictionary into.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Assembled metadata whose length differs from the `metadata_size` the peer
+/// announced in its extended handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataSizeMismatch {
+    pub expected: usize,
+    pub received: usize,
+}
+
+impl MetadataSizeMismatch {
+    /// Index of the first metadata piece missing bytes, which should be
+    /// fetched again, or `None` if there were too many bytes instead.
+    pub fn first_incomplete_piece(&self) -> Option<usize> {
+        (self.received < self.expected).then_some(self.received / METADATA_PIECE_SIZE)
+    }
+}
+
+impl std::fmt::Display for MetadataSizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received {} bytes of metadata but expected {}",
+            self.received, self.expected
+        )
+    }
+}
+
+impl std::error::Error for MetadataSizeMismatch {}
+
+/// Parses the info dictionary reassembled from `ut_metadata` pieces. If its
+/// length is not `metadata_size`, the error is a [`MetadataSizeMismatch`].
+pub fn parse_metadata(assembled: &[u8], metadata_size: usize) -> Result<Info> {
+    let info = serde_bencode::from_bytes(assembled).context("deserialize metadata");
+    if assembled.len() != metadata_size {
+        let mismatch = MetadataSizeMismatch {
+            expected: metadata_size,
+            received: assembled.len(),
+        };
+        return Err(match info {
+            Ok(_) => mismatch.into(),
+            Err(e) => e.context(mismatch),
+        });
+    }
+    info
+}
+
+#[test]
+fn extended_handshake_fields() {
+    let payload = b"\x00d1:md11:ut_metadatai3ee13:metadata_sizei31235e4:reqqi250e1:v6:mock 1e";
+    let handshake = ExtendedHandshake::from_payload(payload).unwrap();
+    assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+    assert_eq!(handshake.reqq, Some(250));
+    assert_eq!(handshake.metadata_size, Some(31235));
+    assert_eq!(
+        ExtendedHandshake::from_payload(&handshake.to_payload().unwrap()).unwrap(),
+        handshake
+    );
+}
+
+#[test]
+fn short_metadata_reports_shortfall() {
+    let t = crate::mock::torrent(4, b"0123456789");
+    let metadata = serde_bencode::to_bytes(&t.info).unwrap();
+    assert_eq!(parse_metadata(&metadata, metadata.len()).unwrap(), t.info);
+
+    let short = &metadata[..metadata.len() - 1];
+    let err = parse_metadata(short, metadata.len()).unwrap_err();
+    let mismatch = err
+        .downcast_ref::<MetadataSizeMismatch>()
+        .expect("size mismatch");
+    assert_eq!(
+        *mismatch,
+        MetadataSizeMismatch {
+            expected: metadata.len(),
+            received: metadata.len() - 1,
+        }
+    );
+    assert_eq!(mismatch.first_incomplete_piece(), Some(0));
+    assert!(format!("{err:#}").contains("but expected"), "{err:#}");
+}
@@ -1,7 +1,22 @@
+pub mod bencode;
+pub mod checksum;
+mod disk;
 pub mod download;
+pub mod extension;
+pub mod magnet;
 pub mod peer;
 pub mod piece;
+mod rate;
+pub mod resume;
+pub mod seed;
+pub mod socks;
+pub mod space;
+pub mod status;
 pub mod torrent;
 pub mod tracker;
+pub mod verify;
+
+#[cfg(test)]
+mod mock;
 
 const BLOCK_MAX_SIZE: u32 = 1 << 14;
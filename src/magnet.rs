@@ -0,0 +1,115 @@
+//! Helpers for magnet-link style torrent identifiers.
+
+use anyhow::{Context, Result, bail};
+
+/// What a `magnet:` URI tells us about a torrent before we have its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`).
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`), in the order given.
+    pub trackers: Vec<String>,
+    /// Total length in bytes (`xl`), if the link carries it.
+    pub length: Option<u64>,
+}
+
+impl Magnet {
+    /// Parses a `magnet:?xt=urn:btih:...` URI.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = reqwest::Url::parse(uri).context("parse magnet URI")?;
+        anyhow::ensure!(url.scheme() == "magnet", "not a magnet URI: {uri}");
+        let (mut info_hash, mut name, mut trackers, mut length) = (None, None, Vec::new(), None);
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "xl" => length = Some(value.parse().context("parse magnet length")?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.context("magnet URI has no urn:btih info hash")?,
+            name,
+            trackers,
+            length,
+        })
+    }
+}
+
+#[test]
+fn magnet_parts() {
+    let magnet = Magnet::parse(
+        "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample%20file&tr=udp%3A%2F%2Ft%3A80&xl=42",
+    )
+    .unwrap();
+    assert_eq!(magnet.info_hash[0], 0xd6);
+    assert_eq!(magnet.name.as_deref(), Some("sample file"));
+    assert_eq!(magnet.trackers, ["udp://t:80"]);
+    assert_eq!(magnet.length, Some(42));
+    assert!(Magnet::parse("magnet:?dn=x").is_err());
+}
+
+/// Parses an info hash written as 40 hex digits or 32 base32 characters, the
+/// two encodings magnet links use.
+pub fn parse_info_hash(s: &str) -> Result<[u8; 20]> {
+    match s.len() {
+        40 => {
+            let bytes = hex::decode(s).context("decode hex info hash")?;
+            Ok(bytes.try_into().expect("40 hex digits are 20 bytes"))
+        }
+        32 => base32_decode(s).context("decode base32 info hash"),
+        len => bail!("info hash must be 40 hex or 32 base32 characters, got {len}"),
+    }
+}
+
+fn base32_decode(s: &str) -> Result<[u8; 20]> {
+    let mut out = [0u8; 20];
+    let (mut buf, mut bits, mut i) = (0u64, 0, 0);
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => bail!("invalid base32 character {:?}", c as char),
+        };
+        buf = (buf << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out[i] = (buf >> bits) as u8;
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+#[test]
+fn info_hash_hex() {
+    let hash = parse_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+    assert_eq!(hash[..4], [0xd6, 0x9f, 0x91, 0xe6]);
+    assert_eq!(hash[19], 0x7f);
+}
+
+#[test]
+fn info_hash_base32() {
+    let hex = parse_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+    assert_eq!(
+        parse_info_hash("22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap(),
+        hex
+    );
+    assert_eq!(
+        parse_info_hash("22pzdzvsvzgfijdi2edtu4ou5ijypgt7").unwrap(),
+        hex
+    );
+}
+
+#[test]
+fn info_hash_wrong_length() {
+    let err = parse_info_hash("d69f91e6b2ae4c54").unwrap_err();
+    assert!(err.to_string().contains("got 16"), "{err}");
+}
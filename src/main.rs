@@ -1,14 +1,17 @@
 use anyhow::Context;
 use bittorrent_rust::{
-    peer::{Handshake, Message, MessageFramer, MessageTag, Piece, Request},
+    bencode::decode_bencoded_bytes,
+    checksum::Checksum,
+    download::{DownloadConfig, HashMismatch, create_output, verify_existing},
+    peer::{Handshake, Message, MessageFramer, MessageTag, PeerId, Piece, Request},
+    seed::{FileStore, SeedConfig, Seeder},
     torrent::*,
     tracker::*,
 };
 use clap::{Parser, Subcommand};
 use futures_util::{SinkExt, StreamExt};
-use serde_json;
 use sha1::{Digest, Sha1};
-use std::{net::SocketAddrV4, path::PathBuf, str::FromStr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 const BLOCK_MAX_SIZE: usize = 1 << 14;
@@ -23,7 +26,7 @@ struct Cli {
 #[clap(rename_all = "snake_case")]
 enum Commands {
     Decode {
-        value: String,
+        value: std::ffi::OsString,
     },
     Info {
         torrent: PathBuf,
@@ -35,65 +38,171 @@ enum Commands {
         torrent: PathBuf,
         peer: String,
     },
+    HandshakeHash {
+        info_hash: String,
+        peer: String,
+    },
     DownloadPiece {
+        /// Where to write the piece, `-` for stdout.
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
+        /// Permissions of the created file, in octal (Unix only).
+        #[arg(long, value_parser = parse_mode)]
+        file_mode: Option<u32>,
     },
     Download {
+        /// Where to write the data, `-` for stdout. Multi-file torrents are
+        /// written to files below this directory.
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
+        /// Permissions of the created file, in octal (Unix only).
+        #[arg(long, value_parser = parse_mode)]
+        file_mode: Option<u32>,
+        /// Start even if the output volume looks too full.
+        #[arg(long)]
+        no_space_check: bool,
+        /// Expected SHA-256 (or SHA-1) of the whole download, in hex. Checked
+        /// before writing to stdout, or by reading the files back once they
+        /// are written.
+        #[arg(long)]
+        checksum: Option<Checksum>,
+        /// Keep the pieces an interrupted download already wrote to the
+        /// output and fetch only the rest.
+        #[arg(long = "continue")]
+        continue_download: bool,
+    },
+    Trackers {
+        torrent: PathBuf,
+    },
+    Plan {
+        torrent: PathBuf,
+    },
+    /// Check the data at `path`, a file or a multi-file torrent's directory,
+    /// against the torrent's piece hashes.
+    Verify {
+        torrent: PathBuf,
+        path: PathBuf,
+    },
+    /// Show which piece and block hold a byte of one of the torrent's files.
+    Locate {
+        torrent: PathBuf,
+        /// Index of the file, in the order `info` lists them.
+        file: usize,
+        /// Byte offset within the file.
+        offset: u64,
+    },
+    Seed {
+        torrent: PathBuf,
+        data: PathBuf,
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Listen on the first free port of a range such as `6881-6889`
+        /// instead of `--port`.
+        #[arg(long, value_parser = parse_port_range)]
+        port_range: Option<std::ops::RangeInclusive<u16>>,
     },
 }
 
-fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<(serde_json::Value, &str)> {
-    match encoded_value.bytes().next() {
-        Some(b'i') => {
-            if let Some((n, rest)) = encoded_value
-                .strip_prefix('i')
-                .and_then(|rest| (&*rest).split_once('e'))
-            {
-                let n = n.parse::<i64>()?;
-                return Ok((n.into(), rest));
-            }
-        }
-        Some(b'l') => {
-            let mut items = vec![];
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.starts_with('e') {
-                let (v, reminder) = decode_bencoded_value(rest)?;
-                items.push(v);
-                rest = reminder;
-            }
-            return Ok((items.into(), &rest[1..]));
-        }
-        Some(b'd') => {
-            let mut items = serde_json::Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.starts_with('e') {
-                let (k, reminder) = decode_bencoded_value(rest)?;
-                let k = match k {
-                    serde_json::Value::String(k) => k,
-                    _ => anyhow::bail!("Dictionary keys must be strings, found: {}", k),
-                };
-                let (v, reminder) = decode_bencoded_value(reminder)?;
-                items.insert(k, v);
-                rest = reminder;
-            }
-            return Ok((items.into(), &rest[1..]));
-        }
-        Some(b'0'..=b'9') => {
-            if let Some((len, rest)) = encoded_value.split_once(':') {
-                if let Ok(len) = len.parse::<usize>() {
-                    return Ok((rest[..len].to_string().into(), &rest[len..]));
-                }
+/// `-` as an output path stands for stdout.
+fn is_stdout(output: &std::path::Path) -> bool {
+    output.as_os_str() == "-"
+}
+
+/// Writes `bytes` to the file at `output`, or as-is to `stdout` for `-`.
+async fn write_output(
+    output: &std::path::Path,
+    bytes: &[u8],
+    mut stdout: impl tokio::io::AsyncWrite + Unpin,
+    config: &DownloadConfig,
+) -> anyhow::Result<()> {
+    if is_stdout(output) {
+        stdout.write_all(bytes).await.context("write to stdout")?;
+        stdout.flush().await.context("flush stdout")
+    } else {
+        let mut file = create_output(output, config).await?;
+        file.write_all(bytes)
+            .await
+            .with_context(|| format!("write {}", output.display()))?;
+        file.flush()
+            .await
+            .with_context(|| format!("write {}", output.display()))
+    }
+}
+
+/// Writes what the `info` command shows about `t`: a single `Length:` line
+/// for one file, or each file with its length for several.
+fn write_info(t: &Torrent, out: &mut impl std::io::Write) -> std::io::Result<()> {
+    writeln!(out, "Tracker URL: {}", t.announce)?;
+    match &t.info.keys {
+        Keys::SingleFile { length } => writeln!(out, "Length: {length}")?,
+        Keys::MultiFile { files } => {
+            writeln!(out, "Files:")?;
+            for file in files {
+                let path = file.path.join(std::path::MAIN_SEPARATOR_STR);
+                writeln!(out, "  {path} ({} bytes)", file.length)?;
             }
         }
-        _ => {}
     }
-    anyhow::bail!("Invalid bencoded value: {}", encoded_value)
+    writeln!(out, "Info Hash: {}", hex::encode(t.info_hash()))?;
+    writeln!(out, "Piece Length: {}", t.info.piece_length)?;
+    writeln!(out, "Piece Hashes:")?;
+    for hash in &t.info.pieces.0 {
+        writeln!(out, "{}", hex::encode(hash))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn info_lists_each_file() {
+    let mut dot_torrent = b"d8:announce25:http://127.0.0.1/announce4:infod5:filesl".to_vec();
+    dot_torrent.extend_from_slice(b"d6:lengthi3e4:pathl1:aee");
+    dot_torrent.extend_from_slice(b"d6:lengthi2e4:pathl3:sub1:beee");
+    dot_torrent.extend_from_slice(b"4:name3:dir12:piece lengthi8e6:pieces20:");
+    dot_torrent.extend_from_slice(&[0xab; 20]);
+    dot_torrent.extend_from_slice(b"ee");
+    let t: Torrent = serde_bencode::from_bytes(&dot_torrent).unwrap();
+
+    let mut out = Vec::new();
+    write_info(&t, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let sub_b = ["sub", "b"].join(std::path::MAIN_SEPARATOR_STR);
+    assert!(out.contains("  a (3 bytes)\n"), "{out}");
+    assert!(out.contains(&format!("  {sub_b} (2 bytes)\n")), "{out}");
+    assert!(!out.contains("Length: 5"), "{out}");
+    assert!(out.contains(&format!("Info Hash: {}", hex::encode(t.info_hash()))));
+    assert!(out.ends_with(&format!("{}\n", hex::encode([0xab; 20]))));
+}
+
+/// Parses permission bits written in octal, like `chmod` takes them.
+fn parse_mode(mode: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+}
+
+fn parse_port_range(range: &str) -> anyhow::Result<std::ops::RangeInclusive<u16>> {
+    let (start, end) = range
+        .split_once('-')
+        .context("expected a range like 6881-6889")?;
+    let (start, end): (u16, u16) = (start.parse()?, end.parse()?);
+    anyhow::ensure!(start <= end, "range {start}-{end} is empty");
+    Ok(start..=end)
+}
+
+#[tokio::test]
+async fn dash_writes_bytes_to_stdout() {
+    let piece: Vec<u8> = (0..=255u8).chain([b'\r', b'\n', 0]).collect();
+    let mut stdout = Vec::new();
+    write_output(
+        std::path::Path::new("-"),
+        &piece,
+        &mut stdout,
+        &DownloadConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(stdout, piece);
 }
 
 #[tokio::main]
@@ -105,7 +214,7 @@ async fn main() -> anyhow::Result<()> {
             // let v: serde_json::Value =
             //     serde_bencode::from_str(&value).context("decode bencoded value")?;
 
-            let v = decode_bencoded_value(&value)
+            let v = decode_bencoded_bytes(value.as_encoded_bytes())
                 .context("decode bencoded value")?
                 .0
                 .to_string();
@@ -115,49 +224,26 @@ async fn main() -> anyhow::Result<()> {
         Commands::Info { torrent } => {
             // Handle the Info command
             let torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&torrent).context("deserialize torrent file")?;
-
-            println!("Tracker URL: {}", t.announce);
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
-
-            println!("Length: {length}");
+            let t = Torrent::from_bytes(&torrent)?;
 
-            let info_hash = t.info_hash();
-
-            println!("Info Hash: {}", hex::encode(info_hash));
-
-            println!("Piece Length: {}", t.info.piece_length);
-
-            println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(hash));
-            }
+            write_info(&t, &mut std::io::stdout().lock()).context("print torrent info")?;
         }
         Commands::Peers { torrent } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("deserialize torrent file")?;
+            let t = Torrent::from_bytes(&dot_torrent)?;
 
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
+            let length = t.length();
 
             let info_hash = t.info_hash();
 
             let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
+                peer_id: PeerId::session().to_string(),
                 port: 6881,
                 uploaded: 0,
                 downloaded: 0,
                 left: length,
                 compact: 1,
+                event: None,
             };
 
             let mut tracker_url =
@@ -175,24 +261,23 @@ async fn main() -> anyhow::Result<()> {
             let response: TrackerResponse =
                 serde_bencode::from_bytes(&response).context("deserialize tracker response")?;
 
-            for peer in response.peers.0 {
+            for peer in response.all_peers() {
                 println!("{} {}", peer.ip(), peer.port());
             }
         }
         Commands::Handshake { torrent, peer } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("deserialize torrent file")?;
+            let t = Torrent::from_bytes(&dot_torrent)?;
 
             let info_hash = t.info_hash();
 
-            let peer = SocketAddrV4::from_str(peer.as_str()).context("parse peer address")?;
+            let peer = SocketAddr::from_str(peer.as_str()).context("parse peer address")?;
 
             let mut peer = tokio::net::TcpStream::connect(peer)
                 .await
                 .context("connect to peer")?;
 
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            let mut handshake = Handshake::new(info_hash, *PeerId::session().as_bytes());
             {
                 let handshake_bytes = handshake.as_bytes_mut();
 
@@ -204,33 +289,51 @@ async fn main() -> anyhow::Result<()> {
                     .await
                     .context("read handshake")?;
             }
+            handshake.verify(info_hash)?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+        }
+        Commands::HandshakeHash { info_hash, peer } => {
+            let info_hash = bittorrent_rust::magnet::parse_info_hash(&info_hash)?;
+            let peer = SocketAddr::from_str(peer.as_str()).context("parse peer address")?;
+
+            let mut peer = tokio::net::TcpStream::connect(peer)
+                .await
+                .context("connect to peer")?;
+            let handshake = bittorrent_rust::peer::handshake(&mut peer, info_hash).await?;
+
             println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            let capabilities = handshake.capabilities();
+            if capabilities.is_empty() {
+                println!("Capabilities: none");
+            } else {
+                println!("Capabilities: {}", capabilities.join(", "));
+            }
         }
         Commands::DownloadPiece {
             output,
             torrent,
             piece,
+            file_mode,
         } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("deserialize torrent file")?;
-            assert!(piece < t.info.pieces.0.len(), "Piece index out of bounds");
+            let t = Torrent::from_bytes(&dot_torrent)?;
+            // Validation keeps the piece index and in-piece offsets below
+            // within u32 for the casts in the requests.
+            t.validate().context("invalid torrent")?;
+            anyhow::ensure!(piece < t.info.pieces.0.len(), "Piece index out of bounds");
 
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
+            let length = t.length();
 
             let info_hash = t.info_hash();
 
             let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
+                peer_id: PeerId::session().to_string(),
                 port: 6881,
                 uploaded: 0,
                 downloaded: 0,
                 left: length,
                 compact: 1,
+                event: None,
             };
 
             let mut tracker_url =
@@ -248,13 +351,14 @@ async fn main() -> anyhow::Result<()> {
             let response: TrackerResponse =
                 serde_bencode::from_bytes(&response).context("deserialize tracker response")?;
 
-            let peer = response.peers.0.first().context("no peers found")?;
+            let peers = response.all_peers();
+            let peer = peers.first().context("no peers found")?;
 
             let mut peer = tokio::net::TcpStream::connect(peer)
                 .await
                 .context("connect to peer")?;
 
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            let mut handshake = Handshake::new(info_hash, *PeerId::session().as_bytes());
             {
                 let handshake_bytes = handshake.as_bytes_mut();
 
@@ -266,8 +370,12 @@ async fn main() -> anyhow::Result<()> {
                     .await
                     .context("read handshake")?;
             }
+            handshake.verify(info_hash)?;
 
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
+            let mut peer = tokio_util::codec::Framed::new(
+                peer,
+                MessageFramer::for_pieces(t.info.pieces.0.len()),
+            );
 
             let bit_field = peer
                 .next()
@@ -289,15 +397,13 @@ async fn main() -> anyhow::Result<()> {
             assert_eq!(un_choke.tag, MessageTag::UnChoke);
             assert!(un_choke.payload.is_empty());
 
-            let piece_hash = t.info.pieces.0[piece];
-            let piece_size = if piece == t.info.pieces.0.len() - 1 {
-                let md = length % t.info.piece_length;
-                if md == 0 { t.info.piece_length } else { md }
-            } else {
-                t.info.piece_length
-            };
+            let (_, _, piece_size, &piece_hash) = t
+                .info
+                .pieces_with_offsets()
+                .nth(piece)
+                .context("piece index out of bounds")?;
 
-            let blocks_num = (piece_size + BLOCK_MAX_SIZE - 1) / BLOCK_MAX_SIZE;
+            let blocks_num = piece_size.div_ceil(BLOCK_MAX_SIZE);
             let mut all_blocks = Vec::with_capacity(piece_size);
             for block in 0..blocks_num {
                 let block_size = if block == blocks_num - 1 {
@@ -333,24 +439,223 @@ async fn main() -> anyhow::Result<()> {
             let mut hasher = Sha1::new();
             hasher.update(&all_blocks);
             let hash: [u8; 20] = hasher.finalize().into();
-            assert_eq!(hash, piece_hash, "Piece hash mismatch");
+            if hash != piece_hash {
+                return Err(HashMismatch { index: piece }.into());
+            }
 
-            tokio::fs::write(&output, all_blocks)
+            let config = DownloadConfig {
+                file_mode,
+                ..Default::default()
+            };
+            write_output(&output, &all_blocks, tokio::io::stdout(), &config)
                 .await
                 .context("write piece to output file")?;
-            println!("Piece {piece} downloaded to {}", output.display())
+            if !is_stdout(&output) {
+                println!("Piece {piece} downloaded to {}", output.display())
+            }
+        }
+        Commands::Download {
+            output,
+            torrent,
+            file_mode,
+            no_space_check,
+            checksum,
+            continue_download,
+        } => {
+            let torrent = Torrent::read(torrent).await.context("read torrent file")?;
+            torrent.validate().context("invalid torrent")?;
+            if !no_space_check && !is_stdout(&output) {
+                let dir = match output.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => std::path::Path::new("."),
+                };
+                bittorrent_rust::space::check_space(&torrent, dir)?;
+            }
+            if !is_stdout(&output) {
+                torrent.print_tree();
+            }
+
+            let multi_file = matches!(torrent.info.keys, Keys::MultiFile { .. });
+            anyhow::ensure!(
+                !(multi_file && is_stdout(&output)),
+                "a multi-file torrent needs an output directory"
+            );
+
+            anyhow::ensure!(
+                !(continue_download && is_stdout(&output)),
+                "--continue needs an output file to continue"
+            );
+
+            let mut config = DownloadConfig {
+                file_mode,
+                ..Default::default()
+            };
+            if continue_download {
+                let have = verify_existing(&torrent, &output)
+                    .await
+                    .context("check existing output")?;
+                println!(
+                    "{}/{} pieces already downloaded",
+                    have.pieces().count(),
+                    torrent.info.pieces.0.len()
+                );
+                config.have = Some(have);
+            }
+            if is_stdout(&output) {
+                let files = torrent
+                    .download_all_with(&config)
+                    .await
+                    .context("download all")?;
+                if let Some(checksum) = checksum {
+                    checksum.verify(files.bytes())?;
+                }
+                write_output(&output, files.bytes(), tokio::io::stdout(), &config)
+                    .await
+                    .context("write downloaded data to stdout")?;
+            } else {
+                torrent
+                    .download_to_disk(&output, &config)
+                    .await
+                    .context("download to disk")?;
+                let paths = torrent.output_paths(&output)?;
+                if let Some(checksum) = checksum {
+                    let mut hasher = checksum.hasher();
+                    let mut buf = vec![0u8; 1 << 16];
+                    for path in &paths {
+                        let mut file = tokio::fs::File::open(path)
+                            .await
+                            .with_context(|| format!("open {}", path.display()))?;
+                        loop {
+                            let n = file
+                                .read(&mut buf)
+                                .await
+                                .with_context(|| format!("read back {}", path.display()))?;
+                            if n == 0 {
+                                break;
+                            }
+                            hasher.update(&buf[..n]);
+                        }
+                    }
+                    hasher.verify()?;
+                }
+                for path in paths {
+                    println!("Downloaded {}", path.display());
+                }
+            }
         }
-        Commands::Download { output, torrent } => {
+        Commands::Trackers { torrent } => {
             let torrent = Torrent::read(torrent).await.context("read torrent file")?;
-            torrent.print_tree();
 
-            let files = torrent.download_all().await.context("download all")?;
-            tokio::fs::write(
-                &output,
-                files.into_iter().next().context("no files")?.bytes(),
-            )
+            let mut client = TrackerClient::new();
+            if let Err(e) = client.query(&torrent).await {
+                eprintln!("announce failed: {e:?}");
+            }
+
+            for (tracker, stats) in client.tracker_stats() {
+                println!("{tracker}");
+                for record in &stats.history {
+                    let at = record
+                        .at
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    match (&record.peers, record.interval) {
+                        (Ok(peers), Some(interval)) => {
+                            println!("  {at}: {peers} peers, interval {interval}s")
+                        }
+                        (Ok(peers), None) => println!("  {at}: {peers} peers"),
+                        (Err(e), _) => println!("  {at}: error: {e}"),
+                    }
+                }
+            }
+        }
+        Commands::Plan { torrent } => {
+            let t = Torrent::read(torrent).await.context("read torrent file")?;
+            println!("{}", t.plan());
+        }
+        Commands::Verify { torrent, path } => {
+            let t = Torrent::read(torrent).await.context("read torrent file")?;
+            let verified = bittorrent_rust::verify::verify_files(&t, &path)
+                .await
+                .with_context(|| format!("verify {}", path.display()))?;
+            for (piece_i, ok) in verified.iter().enumerate() {
+                println!("Piece {piece_i}: {}", if *ok { "ok" } else { "FAILED" });
+            }
+            let passed = verified.iter().filter(|ok| **ok).count();
+            println!("{passed}/{} pieces passed", verified.len());
+            anyhow::ensure!(
+                passed == verified.len(),
+                "{} pieces failed",
+                verified.len() - passed
+            );
+        }
+        Commands::Locate {
+            torrent,
+            file,
+            offset,
+        } => {
+            let t = Torrent::read(torrent).await.context("read torrent file")?;
+            let (piece, begin) = t
+                .locate(file, offset)
+                .with_context(|| format!("file {file} has no byte {offset}"))?;
+            println!("Piece: {piece}");
+            println!("Block begin: {begin}");
+        }
+        Commands::Seed {
+            torrent,
+            data,
+            port,
+            port_range,
+        } => {
+            let t = Torrent::read(torrent).await.context("read torrent file")?;
+
+            let verified = bittorrent_rust::verify::verify_file(&t, &data, 16, |done, total| {
+                eprint!("\rverified {done}/{total} pieces")
+            })
             .await
-            .context("write downloaded data to output file")?;
+            .context("verify local data")?;
+            eprintln!();
+            let missing = verified.iter().filter(|&&ok| !ok).count();
+            anyhow::ensure!(
+                missing == 0,
+                "{missing} pieces of {} are missing or corrupt",
+                data.display()
+            );
+
+            let config = SeedConfig {
+                port_range,
+                ..Default::default()
+            };
+            let listener = bittorrent_rust::seed::listen(&config, port).await?;
+            let port = listener.local_addr().context("listener address")?.port();
+            eprintln!("listening on port {port}");
+            let store = FileStore::open(&data, t.info.piece_length)?;
+            let seeder = tokio::spawn(Seeder::new(&t, store, &config).run(listener));
+
+            let mut client = TrackerClient::new();
+            let mut event = Some(AnnounceEvent::Completed);
+            let mut schedule = AnnounceSchedule::new(None);
+            loop {
+                match client.query_event(&t, port, 0, event.take()).await {
+                    Ok(response) => schedule.announced(Some(&response)),
+                    Err(e) => {
+                        eprintln!("announce failed: {e:?}");
+                        schedule.announced(None);
+                    }
+                }
+                tokio::select! {
+                    _ = schedule.wait() => {}
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+
+            seeder.abort();
+            if let Err(e) = client
+                .query_event(&t, port, 0, Some(AnnounceEvent::Stopped))
+                .await
+            {
+                eprintln!("announce failed: {e:?}");
+            }
         }
     }
 
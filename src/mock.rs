@@ -0,0 +1,174 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    peer::{Handshake, MessageFramer},
+    seed::{MemoryStore, SeedConfig, Seeder},
+    torrent::{Hashes, Info, Keys, Torrent},
+};
+
+/// Builds a single-file torrent describing `data`.
+pub(crate) fn torrent(piece_length: usize, data: &[u8]) -> Torrent {
+    Torrent {
+        announce: String::from("http://127.0.0.1/announce"),
+        announce_list: None,
+        info: Info {
+            name: String::from("mock.bin"),
+            piece_length,
+            pieces: Hashes(
+                data.chunks(piece_length)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            ),
+            keys: Keys::SingleFile { length: data.len() },
+        },
+    }
+}
+
+/// Spawns a peer that has every piece of `t` and serves requests from `data`.
+pub(crate) async fn seeder(t: &Torrent, data: Vec<u8>) -> SocketAddr {
+    let (listener, addr) = listen().await;
+    let store = MemoryStore::new(data, t.info.piece_length);
+    let seeder = Seeder::new(t, store, &SeedConfig::default());
+    tokio::spawn(seeder.run(listener));
+    addr
+}
+
+pub(crate) async fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .expect("bind mock peer");
+    let addr = listener.local_addr().expect("mock peer address");
+    (listener, addr)
+}
+
+/// Accepts one connection and answers its handshake, returning the framed stream.
+pub(crate) async fn accept(
+    listener: &TcpListener,
+    info_hash: [u8; 20],
+) -> Framed<TcpStream, MessageFramer> {
+    accept_with(listener, info_hash, [0u8; 8]).await
+}
+
+/// Like [`accept`], advertising the extensions in `reserved`.
+pub(crate) async fn accept_with(
+    listener: &TcpListener,
+    info_hash: [u8; 20],
+    reserved: [u8; 8],
+) -> Framed<TcpStream, MessageFramer> {
+    let (mut stream, _) = listener.accept().await.expect("accept mock connection");
+
+    let mut handshake = Handshake::new(info_hash, *b"-MOCK00-000000000000");
+    handshake.reserved = reserved;
+    let mut request = [0u8; std::mem::size_of::<Handshake>()];
+    stream
+        .read_exact(&mut request)
+        .await
+        .expect("read handshake");
+    stream
+        .write_all(handshake.as_bytes_mut())
+        .await
+        .expect("write handshake");
+
+    Framed::new(stream, MessageFramer::default())
+}
+
+/// A SOCKS5 proxy without authentication that tunnels one connection and
+/// reports the address it was asked to reach.
+pub(crate) async fn socks5_proxy() -> (SocketAddr, tokio::task::JoinHandle<SocketAddr>) {
+    let (listener, addr) = listen().await;
+    let proxy = tokio::spawn(async move {
+        let (mut client, _) = listener.accept().await.expect("accept proxy client");
+        let mut greeting = [0u8; 3];
+        client
+            .read_exact(&mut greeting)
+            .await
+            .expect("read greeting");
+        assert_eq!(greeting, [5, 1, 0]);
+        client.write_all(&[5, 0]).await.expect("choose no auth");
+
+        let mut request = [0u8; 10];
+        client.read_exact(&mut request).await.expect("read connect");
+        assert_eq!(request[..4], [5, 1, 0, 1]);
+        let target = SocketAddr::from(SocketAddrV4::new(
+            Ipv4Addr::new(request[4], request[5], request[6], request[7]),
+            u16::from_be_bytes([request[8], request[9]]),
+        ));
+        let mut upstream = TcpStream::connect(target).await.expect("connect target");
+        client
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .expect("reply to connect");
+
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        });
+        target
+    });
+    (addr, proxy)
+}
+
+/// Serves one canned HTTP response per connection, in order, and returns the
+/// request lines that were received.
+pub(crate) async fn http_server(
+    responses: Vec<Vec<u8>>,
+) -> (SocketAddr, tokio::task::JoinHandle<Vec<String>>) {
+    let (listener, addr) = listen().await;
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.expect("accept tracker request");
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.expect("read tracker request");
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            requests.push(request.lines().next().unwrap_or_default().to_string());
+            stream
+                .write_all(&response)
+                .await
+                .expect("write tracker response");
+            stream.shutdown().await.ok();
+        }
+        requests
+    });
+    (addr, server)
+}
+
+/// Builds an HTTP/1.1 200 response carrying `body` with the given extra headers.
+pub(crate) fn http_response(headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Returns a fresh path under the system temp directory.
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "bittorrent-rust-{}-{}-{name}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
@@ -1,61 +1,198 @@
-use std::net::SocketAddrV4;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{FutureExt, SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
 };
 use tokio_util::{
     bytes::{Buf, BufMut, BytesMut},
     codec::{Decoder, Encoder, Framed},
 };
 
-use crate::BLOCK_MAX_SIZE;
+use crate::{
+    BLOCK_MAX_SIZE,
+    download::DownloadConfig,
+    extension::{EXTENSION_BIT, ExtendedHandshake},
+};
 
-pub(crate) struct Peer {
+pub struct Peer {
+    addr: SocketAddr,
     stream: Framed<TcpStream, MessageFramer>,
     bit_field: BitField,
+    /// Pieces in the torrent, bounding which bits of `bit_field` count.
+    pieces: usize,
     choked: bool,
+    interested: bool,
+    /// The peer told us it is interested in our pieces, which matters once
+    /// we upload to it.
+    peer_interested: bool,
+    stats: PeerStats,
+    /// Blocks of the current piece we requested but have not received yet,
+    /// with when we requested them.
+    outstanding: Vec<(u32, Instant)>,
+    /// `(index, begin)` of the latest blocks we cancelled, whose answers may
+    /// still be on the way.
+    cancelled: Vec<(u32, u32)>,
+    /// Whether the download is paused, see [`crate::download::DownloadHandle`].
+    paused: tokio::sync::watch::Receiver<bool>,
+    unchoke_timeout: Duration,
+    /// How long we wait for the peer to answer any of our outstanding
+    /// requests before giving up on it.
+    request_timeout: Duration,
+    /// The peer kept us choked past `unchoke_timeout`.
+    snubbed: bool,
+    /// The peer alone supplied a piece that failed its hash check.
+    banned: bool,
+    /// The connection failed while working on a piece.
+    closed: bool,
+    /// When we last sent the peer anything, which decides when it needs a
+    /// keep-alive.
+    last_sent: tokio::time::Instant,
+    /// The peer's extended handshake, if it sent one.
+    extensions: Option<ExtendedHandshake>,
 }
 
 impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr)
-            .await
-            .context("connect to peer")?;
-
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-        {
-            let handshake_bytes = handshake.as_bytes_mut();
+    /// Connects to a peer of a torrent with `pieces` pieces, which bounds the
+    /// size of the BitField it may send.
+    pub async fn new(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        pieces: usize,
+    ) -> anyhow::Result<Self> {
+        Self::with_config(peer_addr, info_hash, pieces, &DownloadConfig::default()).await
+    }
 
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
+    pub async fn with_config(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        pieces: usize,
+        config: &DownloadConfig,
+    ) -> anyhow::Result<Self> {
+        let (peer, reply) = within(config.peer_timeout, "the handshake", async {
+            let mut peer = match &config.proxy {
+                Some(proxy) => {
+                    let mut tunnel = connect(proxy.addr, config.bind_addr)
+                        .await
+                        .context("connect to proxy")?;
+                    crate::socks::handshake(&mut tunnel, proxy, peer_addr).await?;
+                    tunnel
+                }
+                None => connect(peer_addr, config.bind_addr).await?,
+            };
+            let reply = handshake(&mut peer, info_hash).await?;
+            Ok((peer, reply))
+        })
+        .await?;
 
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
+        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer::for_pieces(pieces));
+        if reply.reserved[5] & EXTENSION_BIT != 0 {
+            peer.send(Message {
+                tag: MessageTag::Extended,
+                payload: ExtendedHandshake::default().to_payload()?,
+            })
+            .await
+            .context("send extended handshake")?;
         }
-
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bit_field = peer
-            .next()
+        // The extended handshake may come before the BitField.
+        let mut extensions = None;
+        let bit_field = loop {
+            let message = within(
+                config.peer_timeout,
+                "with its BitField",
+                next_message(&mut peer),
+            )
             .await
-            .context("read message expected BitField")??;
+            .context("read message expected BitField")?;
+            if message.tag == MessageTag::Extended
+                && ExtendedHandshake::is_handshake(&message.payload)
+            {
+                extensions = Some(ExtendedHandshake::from_payload(&message.payload)?);
+                continue;
+            }
+            break message;
+        };
         anyhow::ensure!(bit_field.tag == MessageTag::BitField);
 
         Ok(Self {
+            addr: peer_addr,
             stream: peer,
             bit_field: BitField::from_payload(bit_field.payload),
+            pieces,
             choked: true,
+            interested: false,
+            peer_interested: false,
+            stats: PeerStats::default(),
+            outstanding: Vec::new(),
+            cancelled: Vec::new(),
+            paused: config.handle.subscribe(),
+            unchoke_timeout: config.unchoke_timeout,
+            request_timeout: config.peer_timeout,
+            snubbed: false,
+            banned: false,
+            closed: false,
+            last_sent: tokio::time::Instant::now(),
+            extensions,
         })
     }
 
+    /// Whether the peer advertised `piece`. Claims from peers that keep
+    /// failing to deliver what they advertise, or that sent us a corrupt
+    /// piece, are no longer trusted.
     pub fn has_piece(&self, piece: u32) -> bool {
-        self.bit_field.has_piece(piece)
+        self.is_connected() && !self.stats.is_unreliable() && self.bit_field.has_piece(piece)
     }
 
+    /// Whether the peer is still in use: neither banned nor disconnected.
+    pub(crate) fn is_connected(&self) -> bool {
+        !self.banned && !self.closed
+    }
+
+    /// The pieces the peer told us it has.
+    pub fn bit_field(&self) -> &BitField {
+        &self.bit_field
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops using the peer for any piece.
+    pub(crate) fn ban(&mut self) {
+        self.banned = true;
+    }
+
+    /// Whether the peer failed to unchoke us in time; such peers stay
+    /// connected but are only used when no other peer has a piece.
+    pub(crate) fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    pub(crate) fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    /// A copy of the peer's current state.
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            addr: self.addr,
+            pieces_have: self.bit_field.pieces().filter(|&p| p < self.pieces).count(),
+            pieces_total: self.pieces,
+            choked: self.choked,
+            interested: self.interested,
+            peer_interested: self.peer_interested,
+            bytes_downloaded: self.stats.bytes_downloaded,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn participate(
         &mut self,
         piece_i: u32,
@@ -63,93 +200,1613 @@ impl Peer {
         blocks_num: u32,
         submit: kanal::AsyncSender<u32>,
         tasks: kanal::AsyncReceiver<u32>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        finish: tokio::sync::mpsc::Sender<(SocketAddr, Message)>,
+        endgame: Endgame,
+    ) -> anyhow::Result<()> {
+        self.outstanding.clear();
+        let result = self
+            .participate_blocks(
+                piece_i, piece_size, blocks_num, &submit, tasks, finish, &endgame,
+            )
+            .await;
+        if let Err(e) = &result {
+            // A failed read or write leaves nothing to talk to; a timeout or
+            // misbehaviour alone does not.
+            if e.chain().any(|cause| cause.is::<std::io::Error>()) {
+                self.closed = true;
+            }
+            // Hand our unanswered requests back so the remaining peers pick
+            // them up right away instead of the piece stalling.
+            for (block_i, _) in self.outstanding.drain(..) {
+                self.stats.in_flight -= 1;
+                endgame.given_back(block_i);
+                let _ = submit.send(block_i).await;
+            }
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn participate_blocks(
+        &mut self,
+        piece_i: u32,
+        piece_size: u32,
+        blocks_num: u32,
+        submit: &kanal::AsyncSender<u32>,
+        tasks: kanal::AsyncReceiver<u32>,
+        finish: tokio::sync::mpsc::Sender<(SocketAddr, Message)>,
+        endgame: &Endgame,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(
             self.has_piece(piece_i),
             "peer does not have piece {piece_i}"
         );
-        self.stream
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send message with interested")?;
+        let mut arrivals = endgame.arrivals.subscribe();
+        // When the peer must have answered by, reset only when we send it
+        // requests or it sends us something: blocks other peers deliver in
+        // the meantime do not buy it more time.
+        let mut deadline = tokio::time::Instant::now() + self.request_timeout;
 
         loop {
-            while self.choked {
-                let un_choke = self
-                    .stream
-                    .next()
-                    .await
-                    .context("read message expected UnChoke")??;
-                match un_choke.tag {
-                    MessageTag::UnChoke => {
-                        self.choked = false;
-                        assert!(un_choke.payload.is_empty());
-                        break;
-                    }
-                    MessageTag::Have => {
-                        todo!("update bit field");
-                    }
-                    _ => {}
+            if self.outstanding.is_empty() {
+                self.wait_while_paused().await?;
+                if !self.ensure_unchoked().await? {
+                    // Leave the blocks to peers that will serve them.
+                    break;
                 }
             }
 
-            let Ok(block_i) = tasks.recv().await else {
-                break;
-            };
+            // Keep up to a window of requests in flight so the peer never
+            // idles waiting for our next request.
+            while self.outstanding.len() < self.pipeline_window()
+                && (self.outstanding.is_empty() || !*self.paused.borrow())
+            {
+                let block_i = match tasks.try_recv() {
+                    Ok(Some(block_i)) => block_i,
+                    // Every block is handed out: help with the ones other
+                    // peers are still working on.
+                    Ok(None) => match endgame.duplicate(&self.outstanding) {
+                        Some(block_i) => block_i,
+                        None if self.outstanding.is_empty() => match tasks.recv().await {
+                            Ok(block_i) => block_i,
+                            Err(_) => break,
+                        },
+                        None => break,
+                    },
+                    Err(_) => break,
+                };
+                endgame.requested(block_i);
 
-            let block_size = if block_i == blocks_num - 1 {
-                let md = piece_size % BLOCK_MAX_SIZE;
-                if md == 0 { BLOCK_MAX_SIZE } else { md }
-            } else {
-                BLOCK_MAX_SIZE
-            };
-            let mut request = Request::new(piece_i, block_i * BLOCK_MAX_SIZE, block_size);
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
+                let block_size = if block_i == blocks_num - 1 {
+                    let md = piece_size % BLOCK_MAX_SIZE;
+                    if md == 0 { BLOCK_MAX_SIZE } else { md }
+                } else {
+                    BLOCK_MAX_SIZE
+                };
+                // A Request sent before we are interested and unchoked is
+                // dropped by the peer, stalling the block.
+                debug_assert!(self.interested && !self.choked);
+                let mut request = Request::new(piece_i, block_i * BLOCK_MAX_SIZE, block_size);
+                let request_bytes = Vec::from(request.as_bytes_mut());
+                self.stats.in_flight += 1;
+                self.outstanding.push((block_i, Instant::now()));
+                self.send(Message {
                     tag: MessageTag::Request,
                     payload: request_bytes,
                 })
                 .await
                 .with_context(|| format!("send request for block {block_i}"))?;
+                deadline = tokio::time::Instant::now() + self.request_timeout;
+            }
+            if self.outstanding.is_empty() {
+                break;
+            }
 
-            let piece = self.stream.next().await.context("read piece message")??;
+            // Blocks another peer delivered first are no longer worth waiting
+            // for.
+            self.cancel_requests(piece_i, piece_size, |block_i| endgame.has_arrived(block_i))
+                .await?;
+            if self.outstanding.is_empty() {
+                continue;
+            }
+
+            // Other messages may arrive before the blocks we asked for.
+            let waited = tokio::select! {
+                waited = tokio::time::timeout_at(deadline, next_message(&mut self.stream)) => waited,
+                _ = arrivals.changed() => continue,
+            };
+            let Ok(piece) = waited else {
+                self.stats.record_failure();
+                anyhow::bail!(
+                    "peer did not answer our requests within {:?}",
+                    self.request_timeout
+                );
+            };
+            let piece = piece.context("read piece message")?;
+            deadline = tokio::time::Instant::now() + self.request_timeout;
             match piece.tag {
+                // Checked below, where a payload too short to hold an index
+                // and offset is rejected.
+                MessageTag::Piece => {}
                 MessageTag::Choke => {
-                    self.choked = true;
-                    submit.send(block_i).await.expect("re-submit block index");
+                    self.apply(&piece)?;
+                    self.stats.record_failure();
+                    // The peer drops requests it has not answered yet.
+                    for (block_i, _) in self.outstanding.drain(..) {
+                        self.stats.in_flight -= 1;
+                        endgame.given_back(block_i);
+                        submit.send(block_i).await.expect("re-submit block index");
+                    }
+                    anyhow::ensure!(
+                        !self.stats.is_unreliable(),
+                        "peer keeps failing to deliver pieces it advertised"
+                    );
+                    // Declare interest again before waiting for the unchoke,
+                    // in case the peer choked us for seeming idle.
+                    self.interested = false;
                     continue;
                 }
-                MessageTag::Piece => {
-                    assert!(!piece.payload.is_empty());
+                _ => {
+                    self.apply(&piece)?;
+                    continue;
                 }
-                _ => {}
             }
 
             {
                 let piece = Piece::ref_from_bytes(&piece.payload[..])
                     .context("deserialize piece message")?;
-                assert_eq!(piece.begin(), block_i * BLOCK_MAX_SIZE);
-                assert_eq!(piece.block().len(), block_size as usize);
+                // Only blocks we asked this peer for count; anything else is
+                // dropped before it can reach the piece buffer.
+                let requested = self.outstanding.iter().position(|&(block_i, _)| {
+                    let block_size = if block_i == blocks_num - 1 {
+                        piece_size - block_i * BLOCK_MAX_SIZE
+                    } else {
+                        BLOCK_MAX_SIZE
+                    };
+                    piece.index() == piece_i
+                        && piece.begin() == block_i * BLOCK_MAX_SIZE
+                        && piece.block().len() == block_size as usize
+                });
+                let Some(at) = requested else {
+                    let answer = (piece.index(), piece.begin());
+                    if let Some(at) = self.cancelled.iter().position(|&c| c == answer) {
+                        // Sent before our cancel reached the peer.
+                        self.cancelled.remove(at);
+                        continue;
+                    }
+                    eprintln!(
+                        "discarding unrequested block of piece {} at {} from peer",
+                        piece.index(),
+                        piece.begin()
+                    );
+                    self.stats.record_failure();
+                    anyhow::ensure!(
+                        !self.stats.is_unreliable(),
+                        "peer keeps sending blocks we did not request"
+                    );
+                    continue;
+                };
+                let (block_i, requested_at) = self.outstanding.swap_remove(at);
+                self.stats.in_flight -= 1;
+                endgame.arrived(block_i);
+                self.stats
+                    .record_block(piece.block().len(), requested_at.elapsed());
             }
 
-            finish.send(piece).await.expect("send piece to finisher");
+            finish
+                .send((self.addr, piece))
+                .await
+                .expect("send piece to finisher");
+        }
+
+        Ok(())
+    }
+
+    /// Cancels the requests still outstanding for `piece_i` once the
+    /// download stopped waiting for them.
+    pub(crate) async fn cancel_outstanding(
+        &mut self,
+        piece_i: u32,
+        piece_size: u32,
+    ) -> anyhow::Result<()> {
+        self.cancel_requests(piece_i, piece_size, |_| true).await
+    }
+
+    /// Cancels the outstanding requests for the blocks `cancel` picks.
+    async fn cancel_requests(
+        &mut self,
+        piece_i: u32,
+        piece_size: u32,
+        cancel: impl Fn(u32) -> bool,
+    ) -> anyhow::Result<()> {
+        let (outstanding, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.outstanding)
+            .into_iter()
+            .partition(|&(block_i, _)| cancel(block_i));
+        self.outstanding = kept;
+        self.stats.in_flight -= outstanding.len() as u32;
+        for (block_i, _) in outstanding {
+            let begin = block_i * BLOCK_MAX_SIZE;
+            self.cancelled.push((piece_i, begin));
+            let mut cancel = Request::new(piece_i, begin, (piece_size - begin).min(BLOCK_MAX_SIZE));
+            self.send(Message {
+                tag: MessageTag::Cancel,
+                payload: Vec::from(cancel.as_bytes_mut()),
+            })
+            .await
+            .with_context(|| format!("send cancel for block {block_i}"))?;
+        }
+        let stale = self.cancelled.len().saturating_sub(2 * PIPELINE_DEPTH);
+        self.cancelled.drain(..stale);
+        Ok(())
+    }
+
+    /// Most requests we keep in flight to this peer: our own limit, lowered
+    /// to the `reqq` the peer advertised.
+    pub(crate) fn pipeline_window(&self) -> usize {
+        let reqq = self.extensions.as_ref().and_then(|e| e.reqq);
+        reqq.map_or(PIPELINE_DEPTH, |reqq| reqq.clamp(1, PIPELINE_DEPTH))
+    }
+
+    /// Size of the info dictionary the peer advertised, which bounds a
+    /// metadata fetch from it.
+    pub fn metadata_size(&self) -> Option<usize> {
+        self.extensions.as_ref().and_then(|e| e.metadata_size)
+    }
+
+    /// Applies an `Extended` message. Only the handshake matters to us, we do
+    /// not negotiate any extension messages.
+    fn extended(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        if ExtendedHandshake::is_handshake(payload) {
+            self.extensions = Some(ExtendedHandshake::from_payload(payload)?);
+        }
+        Ok(())
+    }
+
+    /// Tells the peer we no longer want anything from it. The next request
+    /// declares interest again, see [`Self::ensure_unchoked`].
+    pub(crate) async fn lose_interest(&mut self) -> anyhow::Result<()> {
+        if self.interested {
+            self.send(Message {
+                tag: MessageTag::NotInterested,
+                payload: Vec::new(),
+            })
+            .await
+            .context("send message with not interested")?;
+            self.interested = false;
         }
+        Ok(())
+    }
 
+    async fn send(&mut self, message: Message) -> std::io::Result<()> {
+        self.stream.send(message).await?;
+        self.last_sent = tokio::time::Instant::now();
         Ok(())
     }
+
+    /// Sends a keep-alive if we have not sent the peer anything for
+    /// [`KEEP_ALIVE_INTERVAL`], so it does not drop us while we have nothing
+    /// to ask of it.
+    pub(crate) async fn keep_alive(&mut self) -> anyhow::Result<()> {
+        if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.stream
+                .send(KeepAlive)
+                .await
+                .context("send keep-alive")?;
+            self.last_sent = tokio::time::Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Applies the Choke and UnChoke messages the peer already sent, without
+    /// waiting for more.
+    fn catch_up(&mut self) -> anyhow::Result<()> {
+        while let Some(message) = self.stream.next().now_or_never().flatten() {
+            let message = message.context("read pending message")?;
+            self.apply(&message)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a message that tells us about the peer's state rather than
+    /// answering a request.
+    fn apply(&mut self, message: &Message) -> anyhow::Result<()> {
+        match message.tag {
+            MessageTag::Choke => self.choked = true,
+            MessageTag::UnChoke => {
+                self.choked = false;
+                self.snubbed = false;
+            }
+            MessageTag::BitField => {
+                anyhow::bail!("peer sent a second BitField, which is a protocol violation");
+            }
+            MessageTag::Have => {
+                let piece: [u8; 4] = message
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .context("Have payload must be a 4-byte piece index")?;
+                let piece = u32::from_be_bytes(piece);
+                anyhow::ensure!(
+                    (piece as usize) < self.pieces,
+                    "peer sent Have for piece {piece} of {}",
+                    self.pieces
+                );
+                self.bit_field.set_piece(piece);
+            }
+            MessageTag::Interested => self.peer_interested = true,
+            MessageTag::NotInterested => self.peer_interested = false,
+            MessageTag::Extended => self.extended(&message.payload)?,
+            // Answers to requests we already gave up on.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Holds off new requests while the download is paused, sending
+    /// keep-alives so the peer does not drop the idle connection.
+    async fn wait_while_paused(&mut self) -> anyhow::Result<()> {
+        while *self.paused.borrow_and_update() {
+            tokio::select! {
+                changed = self.paused.changed() => {
+                    if changed.is_err() {
+                        // Nobody is left to resume us; carry on.
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(self.last_sent + KEEP_ALIVE_INTERVAL) => {
+                    self.keep_alive().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Declares interest if we have not yet, then waits until the peer
+    /// unchokes us, so requests are only sent when the peer will answer them.
+    /// Returns `false`, marking the peer snubbed, if that takes longer than the
+    /// unchoke timeout.
+    async fn ensure_unchoked(&mut self) -> anyhow::Result<bool> {
+        if !self.interested {
+            // The peer may have choked us while we were not interested.
+            self.catch_up()?;
+            self.send(Message {
+                tag: MessageTag::Interested,
+                payload: Vec::new(),
+            })
+            .await
+            .context("send message with interested")?;
+            self.interested = true;
+        }
+
+        let deadline = tokio::time::Instant::now() + self.unchoke_timeout;
+        while self.choked {
+            let Ok(un_choke) =
+                tokio::time::timeout_at(deadline, next_message(&mut self.stream)).await
+            else {
+                self.snubbed = true;
+                return Ok(false);
+            };
+            let un_choke = un_choke.context("read message expected UnChoke")?;
+            self.apply(&un_choke)?;
+        }
+        Ok(true)
+    }
+}
+
+#[tokio::test]
+async fn requests_wait_for_unchoke() {
+    let info_hash = [9u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+
+        let interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(interested.tag, MessageTag::Interested);
+        let early = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(early.is_err(), "nothing may be requested while choked");
+
+        stream
+            .send(message(MessageTag::UnChoke, Vec::new()))
+            .await
+            .unwrap();
+        let request = stream.next().await.unwrap().unwrap();
+        assert_eq!(request.tag, MessageTag::Request);
+        let mut payload = request.payload[..8].to_vec();
+        payload.extend_from_slice(&[1u8; 16]);
+        stream
+            .send(message(MessageTag::Piece, payload))
+            .await
+            .unwrap();
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, mut done) = tokio::sync::mpsc::channel(1);
+    let participate = peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+    let (participated, block) = tokio::join!(participate, async {
+        let block = done.recv().await.map(|(_, block)| block);
+        submit.close().unwrap();
+        block
+    });
+    participated.expect("download after unchoke");
+    assert_eq!(block.expect("block delivered").tag, MessageTag::Piece);
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn interest_is_declared_again_after_choke() {
+    let info_hash = [9u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let (choked, choked_rx) = tokio::sync::oneshot::channel();
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+
+        let serve = async |stream: &mut Framed<TcpStream, MessageFramer>| {
+            let interested = stream.next().await.unwrap().unwrap();
+            assert_eq!(interested.tag, MessageTag::Interested);
+            stream
+                .send(message(MessageTag::UnChoke, Vec::new()))
+                .await
+                .unwrap();
+            let request = stream.next().await.unwrap().unwrap();
+            assert_eq!(request.tag, MessageTag::Request);
+            let mut payload = request.payload[..8].to_vec();
+            payload.extend_from_slice(&[1u8; 16]);
+            stream
+                .send(message(MessageTag::Piece, payload))
+                .await
+                .unwrap();
+        };
+        serve(&mut stream).await;
+
+        let not_interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(not_interested.tag, MessageTag::NotInterested);
+        stream
+            .send(message(MessageTag::Choke, Vec::new()))
+            .await
+            .unwrap();
+        choked.send(()).unwrap();
+
+        serve(&mut stream).await;
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    let download = async |peer: &mut Peer| {
+        let (submit, tasks) = kanal::bounded_async(1);
+        submit.send(0).await.unwrap();
+        let (finish, mut done) = tokio::sync::mpsc::channel(1);
+        let participate =
+            peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+        let (participated, block) = tokio::join!(participate, async {
+            let block = done.recv().await.map(|(_, block)| block);
+            submit.close().unwrap();
+            block
+        });
+        participated.expect("download block");
+        assert_eq!(block.expect("block delivered").tag, MessageTag::Piece);
+    };
+    download(&mut peer).await;
+
+    peer.lose_interest().await.expect("send not interested");
+    choked_rx.await.unwrap();
+    // Let the Choke reach our socket.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    download(&mut peer).await;
+    assert_eq!(peer.stats().failed, 0, "the choke must not cost a request");
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn reqq_caps_pipelining() {
+    let info_hash = [4u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut reserved = [0u8; 8];
+        reserved[5] = EXTENSION_BIT;
+        let mut stream = crate::mock::accept_with(&listener, info_hash, reserved).await;
+        let message = |tag, payload| Message { tag, payload };
+        let ours = stream.next().await.unwrap().unwrap();
+        assert_eq!(ours.tag, MessageTag::Extended);
+        ExtendedHandshake::from_payload(&ours.payload).expect("our extended handshake");
+        stream
+            .send(message(
+                MessageTag::Extended,
+                b"\x00d1:mde13:metadata_sizei31235e4:reqqi2ee".to_vec(),
+            ))
+            .await
+            .unwrap();
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+
+        let interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(interested.tag, MessageTag::Interested);
+        stream
+            .send(message(MessageTag::UnChoke, Vec::new()))
+            .await
+            .unwrap();
+        let mut served = 0;
+        while served < 4 {
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                let request = stream.next().await.unwrap().unwrap();
+                assert_eq!(request.tag, MessageTag::Request);
+                requests.push(request);
+            }
+            let more = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+            assert!(more.is_err(), "more requests in flight than reqq allows");
+            for request in requests {
+                let mut payload = request.payload[..8].to_vec();
+                payload.extend_from_slice(&[1u8; BLOCK_MAX_SIZE as usize]);
+                stream
+                    .send(message(MessageTag::Piece, payload))
+                    .await
+                    .unwrap();
+                served += 1;
+            }
+        }
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    assert_eq!(peer.metadata_size(), Some(31235));
+    assert_eq!(peer.pipeline_window(), 2);
+
+    let (submit, tasks) = kanal::bounded_async(4);
+    for block_i in 0..4 {
+        submit.send(block_i).await.unwrap();
+    }
+    let (finish, mut done) = tokio::sync::mpsc::channel(4);
+    let participate = peer.participate(
+        0,
+        4 * BLOCK_MAX_SIZE,
+        4,
+        submit.clone(),
+        tasks,
+        finish,
+        Endgame::default(),
+    );
+    let (participated, blocks) = tokio::join!(participate, async {
+        let mut blocks = 0;
+        while blocks < 4 && done.recv().await.is_some() {
+            blocks += 1;
+        }
+        submit.close().unwrap();
+        blocks
+    });
+    participated.expect("download with pipelining");
+    assert_eq!(blocks, 4);
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn block_arrives_after_have() {
+    let info_hash = [6u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        let have = |piece: u32| message(MessageTag::Have, piece.to_be_bytes().to_vec());
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+
+        let interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(interested.tag, MessageTag::Interested);
+        stream.send(have(1)).await.unwrap();
+        stream
+            .send(message(MessageTag::UnChoke, Vec::new()))
+            .await
+            .unwrap();
+        let request = stream.next().await.unwrap().unwrap();
+        assert_eq!(request.tag, MessageTag::Request);
+        stream.send(have(2)).await.unwrap();
+        // A keep-alive.
+        stream.get_mut().write_all(&[0; 4]).await.unwrap();
+        let mut payload = request.payload[..8].to_vec();
+        payload.extend_from_slice(&[1u8; 16]);
+        stream
+            .send(message(MessageTag::Piece, payload))
+            .await
+            .unwrap();
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 3)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, mut done) = tokio::sync::mpsc::channel(1);
+    let participate = peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+    let (participated, block) = tokio::join!(participate, async {
+        let block = done.recv().await.map(|(_, block)| block);
+        submit.close().unwrap();
+        block
+    });
+    participated.expect("download past the Have messages");
+    let block = block.expect("block delivered");
+    assert_eq!(block.tag, MessageTag::Piece);
+    assert_eq!(block.payload[8..], [1u8; 16]);
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn choke_mid_download_waits_for_unchoke() {
+    let info_hash = [2u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+        for round in 0..2 {
+            let interested = stream.next().await.unwrap().unwrap();
+            assert_eq!(interested.tag, MessageTag::Interested, "round {round}");
+            stream
+                .send(message(MessageTag::UnChoke, Vec::new()))
+                .await
+                .unwrap();
+            let request = stream.next().await.unwrap().unwrap();
+            assert_eq!(request.tag, MessageTag::Request);
+            if round == 0 {
+                // Drops the request.
+                stream
+                    .send(message(MessageTag::Choke, Vec::new()))
+                    .await
+                    .unwrap();
+                continue;
+            }
+            let mut payload = request.payload[..8].to_vec();
+            payload.extend_from_slice(&[3u8; 16]);
+            stream
+                .send(message(MessageTag::Piece, payload))
+                .await
+                .unwrap();
+        }
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, mut done) = tokio::sync::mpsc::channel(1);
+    let participate = peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+    let (participated, block) = tokio::join!(participate, async {
+        let block = done.recv().await.map(|(_, block)| block);
+        submit.close().unwrap();
+        block
+    });
+    participated.expect("download across the choke");
+    assert_eq!(block.expect("block delivered").payload[8..], [3u8; 16]);
+    assert!(!peer.snapshot().choked);
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn inbound_interest_is_tracked() {
+    let info_hash = [3u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80]))
+            .await
+            .unwrap();
+        let interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(interested.tag, MessageTag::Interested);
+        stream
+            .send(message(MessageTag::UnChoke, Vec::new()))
+            .await
+            .unwrap();
+        let request = stream.next().await.unwrap().unwrap();
+        assert_eq!(request.tag, MessageTag::Request);
+        // The peer wants something from us in the middle of our download.
+        stream
+            .send(message(MessageTag::Interested, Vec::new()))
+            .await
+            .unwrap();
+        let mut payload = request.payload[..8].to_vec();
+        payload.extend_from_slice(&[2u8; 16]);
+        stream
+            .send(message(MessageTag::Piece, payload))
+            .await
+            .unwrap();
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    assert!(!peer.snapshot().peer_interested);
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, mut done) = tokio::sync::mpsc::channel(1);
+    let participate = peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+    let (participated, block) = tokio::join!(participate, async {
+        let block = done.recv().await.map(|(_, block)| block);
+        submit.close().unwrap();
+        block
+    });
+    participated.expect("download past the Interested message");
+    assert_eq!(block.expect("block delivered").payload[8..], [2u8; 16]);
+    assert!(peer.snapshot().peer_interested);
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn unrequested_block_is_discarded() {
+    let info_hash = [7u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0xc0]))
+            .await
+            .unwrap();
+        let interested = stream.next().await.unwrap().unwrap();
+        assert_eq!(interested.tag, MessageTag::Interested);
+        stream
+            .send(message(MessageTag::UnChoke, Vec::new()))
+            .await
+            .unwrap();
+        let request = stream.next().await.unwrap().unwrap();
+        assert_eq!(request.tag, MessageTag::Request);
+
+        // Same offset, but of piece 1, which we did not ask for.
+        let mut wrong = request.payload[..8].to_vec();
+        wrong[3] = 1;
+        wrong.extend_from_slice(&[0xee; 16]);
+        stream
+            .send(message(MessageTag::Piece, wrong))
+            .await
+            .unwrap();
+        let mut payload = request.payload[..8].to_vec();
+        payload.extend_from_slice(&[1u8; 16]);
+        stream
+            .send(message(MessageTag::Piece, payload))
+            .await
+            .unwrap();
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 2)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, mut done) = tokio::sync::mpsc::channel(2);
+    let participate = peer.participate(0, 16, 1, submit.clone(), tasks, finish, Endgame::default());
+    let (participated, block) = tokio::join!(participate, async {
+        let block = done.recv().await.map(|(_, block)| block);
+        submit.close().unwrap();
+        block
+    });
+    participated.expect("download the requested block");
+    let block = block.expect("block delivered");
+    assert_eq!(block.payload[..4], [0, 0, 0, 0]);
+    assert_eq!(block.payload[8..], [1u8; 16]);
+    assert!(done.try_recv().is_err(), "the stray block never arrives");
+    assert_eq!(peer.stats().failed, 1);
+    mock.await.unwrap();
+}
+
+const MAX_READ_RETRIES: u32 = 3;
+
+/// Reserved handshake bit, in byte 7, advertising the Fast Extension (BEP 6).
+pub(crate) const FAST_BIT: u8 = 0x04;
+
+/// Requests kept in flight to one peer, unless it asks for fewer.
+const PIPELINE_DEPTH: usize = 5;
+
+/// Peers drop connections idle for two minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Reads the next message, retrying transient I/O errors a bounded number of
+/// times. Decode errors (`InvalidData`) and closed connections are fatal.
+async fn next_message<S>(stream: &mut S) -> anyhow::Result<Message>
+where
+    S: futures_core::Stream<Item = std::io::Result<Message>> + Unpin,
+{
+    let mut retries = 0;
+    let mut errored = false;
+    loop {
+        match stream.next().await {
+            Some(Ok(message)) => return Ok(message),
+            Some(Err(e)) if is_transient(&e) && retries < MAX_READ_RETRIES => {
+                retries += 1;
+                errored = true;
+                tokio::time::sleep(Duration::from_millis(50 * retries as u64)).await;
+            }
+            Some(Err(e)) => return Err(e).context("read message from peer"),
+            // `Framed` yields `None` once after an error before it resumes reading.
+            None if errored => errored = false,
+            None => {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+                    .context("peer closed the connection");
+            }
+        }
+    }
+}
+
+/// Only an interrupted read is worth retrying; a stream timing out or
+/// reporting it would block has nothing more coming.
+fn is_transient(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::Interrupted
+}
+
+#[test]
+fn only_interrupted_reads_are_retried() {
+    assert!(is_transient(&std::io::ErrorKind::Interrupted.into()));
+    assert!(!is_transient(&std::io::ErrorKind::TimedOut.into()));
+    assert!(!is_transient(&std::io::ErrorKind::WouldBlock.into()));
+}
+
+#[tokio::test]
+async fn next_message_retries_transient_errors() {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::ReadBuf;
+
+    /// Fails the first read with `Interrupted`, then serves `data`.
+    struct Flaky {
+        failed: bool,
+        data: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if !self.failed {
+                self.failed = true;
+                return Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+            }
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    let mut frame = BytesMut::new();
+    let mut payload = Vec::from(Request::new(0, 0, 0).as_bytes_mut())[..8].to_vec();
+    payload.extend_from_slice(b"block");
+    MessageFramer::default()
+        .encode(
+            Message {
+                tag: MessageTag::Piece,
+                payload: payload.clone(),
+            },
+            &mut frame,
+        )
+        .unwrap();
+
+    let reader = Flaky {
+        failed: false,
+        data: std::io::Cursor::new(frame.to_vec()),
+    };
+    let mut stream = tokio_util::codec::FramedRead::new(reader, MessageFramer::default());
+    let message = next_message(&mut stream).await.expect("retry after error");
+    assert_eq!(message.tag, MessageTag::Piece);
+    assert_eq!(message.payload, payload);
+}
+
+/// A copy of a connected peer's state, for showing it elsewhere, see
+/// [`crate::download::DownloadHandle::peers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSnapshot {
+    pub addr: SocketAddr,
+    /// How many of the torrent's `pieces_total` pieces the peer has.
+    pub pieces_have: usize,
+    pub pieces_total: usize,
+    /// The peer is choking us.
+    pub choked: bool,
+    /// We told the peer we are interested.
+    pub interested: bool,
+    /// The peer told us it is interested.
+    pub peer_interested: bool,
+    /// Block bytes received from the peer.
+    pub bytes_downloaded: u64,
+}
+
+/// Which blocks of a piece are requested from how many peers, shared by the
+/// peers working the piece. Once every block is handed out, peers with room
+/// in their pipeline request blocks others are still waiting for, so one
+/// slow peer does not hold up the piece.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Endgame {
+    requested: Arc<Mutex<HashMap<u32, usize>>>,
+    /// Blocks some peer delivered, whose requests to other peers can be
+    /// cancelled.
+    arrived: Arc<Mutex<HashSet<u32>>>,
+    /// Changes whenever a block arrives, so peers waiting on a duplicate
+    /// request notice and cancel it.
+    arrivals: Arc<tokio::sync::watch::Sender<()>>,
+    /// Most peers a block is requested from at once; below 2 no block is
+    /// requested twice.
+    max_duplicates: usize,
+}
+
+impl Endgame {
+    pub(crate) fn new(max_duplicates: usize) -> Self {
+        Self {
+            requested: Arc::default(),
+            arrived: Arc::default(),
+            arrivals: Arc::default(),
+            max_duplicates,
+        }
+    }
+
+    /// A block still on the way that may be requested once more, preferring
+    /// the ones asked of the fewest peers, skipping those in `outstanding`.
+    fn duplicate(&self, outstanding: &[(u32, Instant)]) -> Option<u32> {
+        let requested = self.requested.lock().expect("endgame requests poisoned");
+        requested
+            .iter()
+            .filter(|&(block_i, &peers)| {
+                peers < self.max_duplicates && outstanding.iter().all(|(b, _)| b != block_i)
+            })
+            .min_by_key(|&(block_i, &peers)| (peers, *block_i))
+            .map(|(&block_i, _)| block_i)
+    }
+
+    fn requested(&self, block_i: u32) {
+        *self
+            .requested
+            .lock()
+            .expect("endgame requests poisoned")
+            .entry(block_i)
+            .or_default() += 1;
+    }
+
+    /// A request for `block_i` went unanswered and the block is queued again.
+    fn given_back(&self, block_i: u32) {
+        let mut requested = self.requested.lock().expect("endgame requests poisoned");
+        if let Some(peers) = requested.get_mut(&block_i) {
+            *peers -= 1;
+            if *peers == 0 {
+                requested.remove(&block_i);
+            }
+        }
+    }
+
+    fn arrived(&self, block_i: u32) {
+        self.requested
+            .lock()
+            .expect("endgame requests poisoned")
+            .remove(&block_i);
+        self.arrived
+            .lock()
+            .expect("endgame arrivals poisoned")
+            .insert(block_i);
+        self.arrivals.send_replace(());
+    }
+
+    fn has_arrived(&self, block_i: u32) -> bool {
+        self.arrived
+            .lock()
+            .expect("endgame arrivals poisoned")
+            .contains(&block_i)
+    }
+}
+
+/// Running measurements of how well a peer has served our requests.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerStats {
+    /// Exponentially weighted download rate, in bytes per second.
+    rate: f64,
+    in_flight: u32,
+    delivered: u32,
+    failed: u32,
+    bytes_downloaded: u64,
+}
+
+impl PeerStats {
+    const RATE_WEIGHT: f64 = 0.3;
+    /// Failed requests after which a peer that fails more often than it
+    /// delivers is no longer trusted.
+    const UNRELIABLE_FAILURES: u32 = 3;
+
+    pub(crate) fn record_block(&mut self, bytes: usize, elapsed: Duration) {
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(1e-3);
+        self.rate = if self.delivered == 0 {
+            rate
+        } else {
+            Self::RATE_WEIGHT * rate + (1.0 - Self::RATE_WEIGHT) * self.rate
+        };
+        self.delivered += 1;
+        self.bytes_downloaded += bytes as u64;
+    }
+
+    pub(crate) fn record_failure(&mut self) {
+        self.failed += 1;
+    }
+
+    pub(crate) fn is_unreliable(&self) -> bool {
+        self.failed >= Self::UNRELIABLE_FAILURES && self.failed > self.delivered
+    }
+
+    /// Higher is better: fast, lightly loaded and reliable peers score best.
+    pub(crate) fn score(&self) -> f64 {
+        let reliability = (self.delivered + 1) as f64 / (self.delivered + self.failed + 2) as f64;
+        self.rate * reliability / (1 + self.in_flight) as f64
+    }
+}
+
+#[tokio::test]
+async fn empty_piece_message_drops_the_peer() {
+    let info_hash = [7u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    // Answers the first request with a Piece message carrying nothing.
+    let garbler = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag| Message {
+            tag,
+            payload: Vec::new(),
+        };
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0x80],
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            let reply = match m.tag {
+                MessageTag::Interested => MessageTag::UnChoke,
+                MessageTag::Request => MessageTag::Piece,
+                _ => continue,
+            };
+            if stream.send(message(reply)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let err = peer
+        .participate(
+            0,
+            BLOCK_MAX_SIZE,
+            1,
+            submit.clone(),
+            tasks.clone(),
+            finish,
+            Endgame::default(),
+        )
+        .await
+        .expect_err("an empty Piece must not be taken as a block");
+    assert!(err.to_string().contains("deserialize piece"), "{err}");
+
+    drop(peer);
+    garbler.await.unwrap();
+}
+
+#[tokio::test]
+async fn peer_that_never_delivers_loses_trust() {
+    let info_hash = [5u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    // Claims the piece, but answers each request by choking and then
+    // unchoking again, so it keeps coming back for more.
+    let liar = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag| Message {
+            tag,
+            payload: Vec::new(),
+        };
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0x80],
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            let replies = match m.tag {
+                MessageTag::Interested => vec![MessageTag::UnChoke],
+                MessageTag::Request => vec![MessageTag::Choke, MessageTag::UnChoke],
+                _ => Vec::new(),
+            };
+            for tag in replies {
+                if stream.send(message(tag)).await.is_err() {
+                    // We gave up on the liar.
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    assert!(peer.has_piece(0));
+    let blocks_num = 4;
+    let (submit, tasks) = kanal::bounded_async(blocks_num as usize);
+    for block_i in 0..blocks_num {
+        submit.send(block_i).await.unwrap();
+    }
+    let (finish, _done) = tokio::sync::mpsc::channel(blocks_num as usize);
+    let participated = tokio::time::timeout(
+        Duration::from_secs(2),
+        peer.participate(
+            0,
+            blocks_num * BLOCK_MAX_SIZE,
+            blocks_num,
+            submit.clone(),
+            tasks.clone(),
+            finish,
+            Endgame::default(),
+        ),
+    )
+    .await
+    .expect("unreliable peer must give up its blocks");
+    assert!(participated.is_err());
+    assert!(
+        !peer.has_piece(0),
+        "advertised pieces are no longer trusted"
+    );
+    assert_eq!(
+        tasks.len(),
+        blocks_num as usize,
+        "every block is back in the queue"
+    );
+
+    assert_eq!(peer.stats().failed, 3);
+
+    drop(peer);
+    liar.await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_foreign_protocol() {
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; std::mem::size_of::<Handshake>()];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut reply = Handshake::new([0u8; 20], [0u8; 20]);
+        reply.bittorrent = *b"SSH-2.0-OpenSSH_9.6";
+        stream.write_all(reply.as_bytes_mut()).await.unwrap();
+        stream
+    });
+
+    let err = Peer::new(addr, [0u8; 20], 1)
+        .await
+        .err()
+        .expect("foreign protocol must be rejected");
+    assert!(err.to_string().contains("BitTorrent protocol"), "{err}");
+    drop(mock);
+}
+
+#[tokio::test]
+async fn dropped_peer_requeues_outstanding_block() {
+    let data: Vec<u8> = (0..8 * BLOCK_MAX_SIZE).map(|i| i as u8).collect();
+    let t = crate::mock::torrent(data.len(), &data);
+    let info_hash = t.info_hash();
+    let seeder = crate::mock::seeder(&t, data.clone()).await;
+
+    // A peer that disconnects as soon as it is asked for a block.
+    let (listener, dropper) = crate::mock::listen().await;
+    let (requested, got_request) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0x80],
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(message)) = stream.next().await {
+            match message.tag {
+                MessageTag::Interested => stream
+                    .send(Message {
+                        tag: MessageTag::UnChoke,
+                        payload: Vec::new(),
+                    })
+                    .await
+                    .unwrap(),
+                MessageTag::Request => break,
+                _ => {}
+            }
+        }
+        let _ = requested.send(());
+    });
+
+    let mut dropper = Peer::new(dropper, info_hash, 1).await.unwrap();
+    let mut seeder = Peer::new(seeder, info_hash, 1).await.unwrap();
+
+    let blocks_num = 8;
+    let (submit, tasks) = kanal::bounded_async(blocks_num as usize);
+    for block_i in 0..blocks_num {
+        submit.send(block_i).await.unwrap();
+    }
+    let (finish, mut done) = tokio::sync::mpsc::channel(blocks_num as usize);
+    let size = data.len() as u32;
+    let dropping = dropper.participate(
+        0,
+        size,
+        blocks_num,
+        submit.clone(),
+        tasks.clone(),
+        finish.clone(),
+        Endgame::default(),
+    );
+    let seeding = seeder.participate(
+        0,
+        size,
+        blocks_num,
+        submit,
+        tasks,
+        finish,
+        Endgame::default(),
+    );
+    let collect = async {
+        let mut received = 0;
+        while received < blocks_num {
+            done.recv().await.expect("block from the remaining peer");
+            received += 1;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::select! {
+            _ = async { tokio::join!(dropping, seeding) } => unreachable!("peers wait for more work"),
+            _ = collect => {}
+        }
+    })
+    .await
+    .expect("piece completes without waiting for a timeout");
+    got_request
+        .await
+        .expect("dropping peer was assigned a block");
+}
+
+/// Sends our handshake for `info_hash` and returns the one the peer answers
+/// with.
+pub async fn handshake<S>(stream: &mut S, info_hash: [u8; 20]) -> anyhow::Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Handshake::new(info_hash, *PeerId::session().as_bytes());
+    handshake.reserved[5] |= EXTENSION_BIT;
+    {
+        let handshake_bytes = handshake.as_bytes_mut();
+
+        stream
+            .write_all(handshake_bytes)
+            .await
+            .context("write handshake")?;
+
+        stream
+            .read_exact(handshake_bytes)
+            .await
+            .context("read handshake")?;
+    }
+    handshake.verify(info_hash)?;
+    Ok(handshake)
+}
+
+#[tokio::test]
+async fn silent_peer_times_out_while_others_deliver() {
+    let info_hash = [6u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    // Unchokes us, then never answers a request.
+    let silent = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0x80],
+            })
+            .await
+            .unwrap();
+        while let Some(Ok(m)) = stream.next().await {
+            if m.tag == MessageTag::Interested {
+                let unchoke = Message {
+                    tag: MessageTag::UnChoke,
+                    payload: Vec::new(),
+                };
+                if stream.send(unchoke).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    peer.request_timeout = Duration::from_millis(300);
+    let endgame = Endgame::new(2);
+    // Other peers keep delivering blocks of other pieces meanwhile.
+    let others = {
+        let endgame = endgame.clone();
+        tokio::spawn(async move {
+            for block_i in 100.. {
+                endgame.arrived(block_i);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+    };
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let participated = tokio::time::timeout(
+        Duration::from_secs(2),
+        peer.participate(0, BLOCK_MAX_SIZE, 1, submit.clone(), tasks, finish, endgame),
+    )
+    .await
+    .expect("arrivals elsewhere must not keep the silent peer alive");
+    others.abort();
+    let err = participated.expect_err("the peer never answered");
+    assert!(err.to_string().contains("did not answer"), "{err}");
+
+    drop(peer);
+    silent.await.unwrap();
+}
+
+#[tokio::test]
+async fn endgame_caps_duplicate_requests() {
+    let info_hash = [4u8; 20];
+    let (requests_tx, mut requests) = tokio::sync::mpsc::unbounded_channel();
+    // Three peers have the piece; none of them ever answers a request.
+    let mut peers = Vec::new();
+    for _ in 0..3 {
+        let (listener, addr) = crate::mock::listen().await;
+        let requests_tx = requests_tx.clone();
+        tokio::spawn(async move {
+            let mut stream = crate::mock::accept(&listener, info_hash).await;
+            let message = |tag, payload| Message { tag, payload };
+            stream
+                .send(message(MessageTag::BitField, vec![0x80]))
+                .await
+                .unwrap();
+            while let Some(Ok(m)) = stream.next().await {
+                match m.tag {
+                    MessageTag::Interested => stream
+                        .send(message(MessageTag::UnChoke, Vec::new()))
+                        .await
+                        .unwrap(),
+                    MessageTag::Request => requests_tx.send(m.payload).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        peers.push(
+            Peer::new(addr, info_hash, 1)
+                .await
+                .expect("connect to mock"),
+        );
+    }
+    drop(requests_tx);
+
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.unwrap();
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let endgame = Endgame::new(2);
+    let participates = futures_util::future::join_all(peers.iter_mut().map(|peer| {
+        peer.participate(
+            0,
+            16,
+            1,
+            submit.clone(),
+            tasks.clone(),
+            finish.clone(),
+            endgame.clone(),
+        )
+    }));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), participates)
+            .await
+            .is_err(),
+        "nobody answers the block"
+    );
+    drop(peers);
+
+    let mut sent = 0;
+    while let Some(request) = requests.recv().await {
+        assert_eq!(request, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 16]);
+        sent += 1;
+    }
+    assert_eq!(
+        sent, 2,
+        "the block is requested from two of the three peers"
+    );
+}
+
+/// Runs `io` with a peer, failing with what the peer did not answer once it
+/// takes longer than `timeout`, so a silent peer is dropped instead of
+/// holding us up.
+async fn within<T>(
+    timeout: Duration,
+    what: &str,
+    io: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, io)
+        .await
+        .map_err(|_| anyhow::anyhow!("peer did not answer {what} within {timeout:?}"))?
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_peer_times_out() {
+    let (mut ours, theirs) = tokio::io::duplex(1024);
+    let timeout = DownloadConfig::default().peer_timeout;
+    let started = tokio::time::Instant::now();
+    let err = within(timeout, "the handshake", handshake(&mut ours, [1u8; 20]))
+        .await
+        .err()
+        .expect("the other side never answers");
+    assert!(
+        err.to_string().contains("did not answer the handshake"),
+        "{err}"
+    );
+    assert_eq!(started.elapsed(), timeout);
+    drop(theirs);
+}
+
+#[tokio::test]
+async fn handshake_reports_peer_id() {
+    let info_hash =
+        crate::magnet::parse_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move { crate::mock::accept(&listener, info_hash).await });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let reply = handshake(&mut stream, info_hash)
+        .await
+        .expect("handshake with mock");
+    assert_eq!(reply.peer_id, *b"-MOCK00-000000000000");
+    assert!(reply.capabilities().is_empty());
+    mock.await.unwrap();
+}
+
+#[tokio::test]
+async fn peer_with_other_info_hash_is_rejected() {
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move { crate::mock::accept(&listener, [8u8; 20]).await });
+
+    let err = Peer::new(addr, [9u8; 20], 1)
+        .await
+        .err()
+        .expect("peer answered for another torrent");
+    assert!(format!("{err:#}").contains("instead of"), "{err:#}");
+    mock.await.unwrap();
+}
+
+async fn connect(
+    peer_addr: SocketAddr,
+    bind_addr: Option<SocketAddr>,
+) -> anyhow::Result<TcpStream> {
+    let Some(bind_addr) = bind_addr else {
+        return TcpStream::connect(peer_addr)
+            .await
+            .context("connect to peer");
+    };
+
+    let socket = if bind_addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .context("create peer socket")?;
+    socket
+        .bind(bind_addr)
+        .with_context(|| format!("bind peer socket to {bind_addr}"))?;
+    socket.connect(peer_addr).await.context("connect to peer")
+}
+
+#[tokio::test]
+async fn connect_through_socks5_proxy() {
+    let info_hash = [4u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0x80],
+            })
+            .await
+            .expect("send bit field");
+        stream
+    });
+    let (proxy, targets) = crate::mock::socks5_proxy().await;
+
+    let config = DownloadConfig {
+        proxy: Some(crate::socks::Socks5Config {
+            addr: proxy,
+            credentials: None,
+        }),
+        ..Default::default()
+    };
+    let peer = Peer::with_config(addr, info_hash, 1, &config)
+        .await
+        .expect("connect through proxy");
+    assert!(peer.has_piece(0));
+    assert_eq!(peer.stream.get_ref().peer_addr().unwrap(), proxy);
+    assert_eq!(targets.await.unwrap(), addr);
+    drop(mock);
 }
 
+#[tokio::test]
+async fn connect_from_bind_addr() {
+    let bind_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("pick a free local port");
+    let info_hash = [3u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let remote = stream.get_ref().peer_addr().expect("remote address");
+        stream
+            .send(Message {
+                tag: MessageTag::BitField,
+                payload: vec![0],
+            })
+            .await
+            .expect("send bit field");
+        remote
+    });
+
+    let config = DownloadConfig {
+        bind_addr: Some(bind_addr),
+        ..Default::default()
+    };
+    let peer = Peer::with_config(addr, info_hash, 1, &config)
+        .await
+        .expect("connect to mock");
+    assert_eq!(peer.stream.get_ref().local_addr().unwrap(), bind_addr);
+    assert_eq!(mock.await.unwrap(), bind_addr);
+}
+
+#[derive(Debug, Clone)]
 pub struct BitField {
     payload: Vec<u8>,
 }
 
 impl BitField {
-    pub(crate) fn has_piece(&self, piece: u32) -> bool {
+    /// A bitfield with none of `pieces` pieces set.
+    pub fn empty(pieces: usize) -> Self {
+        Self::from_payload(vec![0; pieces.div_ceil(u8::BITS as usize)])
+    }
+
+    pub fn has_piece(&self, piece: u32) -> bool {
         let byte_i = piece / u8::BITS;
         let bit_i = piece % u8::BITS;
 
@@ -159,8 +1816,8 @@ impl BitField {
         byte & 1u8.rotate_right(1 + bit_i) != 0
     }
 
-    #[allow(unused)]
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> {
+    /// Indices of the pieces set, in order.
+    pub fn pieces(&self) -> impl Iterator<Item = usize> {
         self.payload.iter().enumerate().flat_map(|(byte_i, &byte)| {
             (0..u8::BITS).filter_map(move |bit_i| {
                 let piece_i = byte_i as u32 * u8::BITS + bit_i;
@@ -170,11 +1827,54 @@ impl BitField {
         })
     }
 
+    /// Pieces set in `self` that were not set in `earlier`, e.g. a snapshot
+    /// of the same peer's bitfield taken before some Have messages.
+    pub fn diff(&self, earlier: &BitField) -> Vec<usize> {
+        self.pieces()
+            .filter(|&piece_i| !earlier.has_piece(piece_i as u32))
+            .collect()
+    }
+
+    /// Marks `piece` as had, growing the bitfield if it was sent short.
+    pub fn set_piece(&mut self, piece: u32) {
+        let byte_i = (piece / u8::BITS) as usize;
+        let bit_i = piece % u8::BITS;
+        if self.payload.len() <= byte_i {
+            self.payload.resize(byte_i + 1, 0);
+        }
+        self.payload[byte_i] |= 1u8.rotate_right(1 + bit_i);
+    }
+
+    /// The bitfield as the peer sent it, with any Have messages applied:
+    /// piece 0 is the high bit of the first byte.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
     fn from_payload(payload: Vec<u8>) -> Self {
         Self { payload }
     }
 }
 
+#[test]
+fn bit_field_as_bytes() {
+    let payload = vec![0b1100_0000, 0b0000_0001];
+    let bf = BitField::from_payload(payload.clone());
+    assert_eq!(bf.as_bytes(), payload);
+}
+
+#[test]
+fn bit_field_set_piece() {
+    let mut bf = BitField {
+        payload: vec![0b1000_0000],
+    };
+    bf.set_piece(10);
+    assert!(bf.has_piece(10));
+    assert_eq!(bf.pieces().collect::<Vec<_>>(), vec![0, 10]);
+    bf.set_piece(0);
+    assert_eq!(bf.payload, vec![0b1000_0000, 0b0010_0000]);
+}
+
 #[test]
 fn bit_field_has() {
     let bf = BitField {
@@ -196,6 +1896,141 @@ fn bit_field_pieces() {
     assert_eq!(pieces, vec![0, 2, 4, 6, 9, 11, 13, 15]);
 }
 
+#[test]
+fn bit_field_diff() {
+    let earlier = BitField {
+        payload: vec![0b1000_0001],
+    };
+    let later = BitField {
+        payload: vec![0b1010_0001, 0b0100_0000],
+    };
+    assert_eq!(later.diff(&earlier), vec![2, 9]);
+    assert!(earlier.diff(&later).is_empty());
+}
+
+#[tokio::test]
+async fn have_adds_piece_to_peer() {
+    let info_hash = [8u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        let message = |tag, payload| Message { tag, payload };
+        stream
+            .send(message(MessageTag::BitField, vec![0x80, 0]))
+            .await
+            .unwrap();
+        stream
+            .send(message(MessageTag::Have, 10u32.to_be_bytes().to_vec()))
+            .await
+            .unwrap();
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 16)
+        .await
+        .expect("connect to mock");
+    assert!(!peer.has_piece(10));
+    let _stream = mock.await.unwrap();
+    let have = next_message(&mut peer.stream).await.expect("read Have");
+    peer.apply(&have).expect("apply Have");
+    assert!(peer.has_piece(10));
+    assert!(peer.has_piece(0));
+}
+
+#[tokio::test]
+async fn duplicate_bit_field_drops_peer() {
+    let info_hash = [7u8; 20];
+    let (listener, addr) = crate::mock::listen().await;
+    let mock = tokio::spawn(async move {
+        let mut stream = crate::mock::accept(&listener, info_hash).await;
+        for _ in 0..2 {
+            stream
+                .send(Message {
+                    tag: MessageTag::BitField,
+                    payload: vec![0b1000_0000],
+                })
+                .await
+                .expect("send bit field");
+        }
+        stream
+    });
+
+    let mut peer = Peer::new(addr, info_hash, 1)
+        .await
+        .expect("connect to mock");
+    let (submit, tasks) = kanal::bounded_async(1);
+    submit.send(0).await.expect("queue block");
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let err = peer
+        .participate(
+            0,
+            BLOCK_MAX_SIZE,
+            1,
+            submit,
+            tasks,
+            finish,
+            Endgame::default(),
+        )
+        .await
+        .expect_err("second BitField must be rejected");
+    assert!(err.to_string().contains("second BitField"), "{err}");
+    drop(mock);
+}
+
+/// Identifies us to trackers and peers: an Azureus-style `-RS0001-` client
+/// prefix followed by 12 random characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    const PREFIX: &[u8; 8] = b"-RS0001-";
+
+    /// A fresh ID. The random part is alphanumeric, so the ID is also valid
+    /// as the string trackers are sent.
+    pub fn random() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+
+        const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        let mut id = [0u8; 20];
+        id[..8].copy_from_slice(Self::PREFIX);
+        let state = std::hash::RandomState::new();
+        for (i, byte) in id[8..].iter_mut().enumerate() {
+            let mut hasher = state.build_hasher();
+            hasher.write_usize(i);
+            *byte = ALPHABET[(hasher.finish() % ALPHABET.len() as u64) as usize];
+        }
+        Self(id)
+    }
+
+    /// The ID of this process, generated on first use, so tracker announces
+    /// and peer handshakes agree on who we are.
+    pub fn session() -> Self {
+        static SESSION: std::sync::OnceLock<PeerId> = std::sync::OnceLock::new();
+        *SESSION.get_or_init(Self::random)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(std::str::from_utf8(&self.0).expect("peer IDs are ASCII"))
+    }
+}
+
+#[test]
+fn random_peer_ids_differ() {
+    let (a, b) = (PeerId::random(), PeerId::random());
+    assert_ne!(a, b);
+    for id in [a, b] {
+        assert!(id.to_string().starts_with("-RS0001-"), "{id}");
+        assert_eq!(id.to_string().len(), 20);
+    }
+    assert_eq!(PeerId::session(), PeerId::session());
+}
+
 #[repr(C)]
 pub struct Handshake {
     pub length: u8,
@@ -216,6 +2051,35 @@ impl Handshake {
         }
     }
 
+    /// Fails unless this is a BitTorrent protocol handshake for `info_hash`,
+    /// so a peer serving some other torrent is not taken for one of ours.
+    pub fn verify(&self, info_hash: [u8; 20]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.length == 19 && self.bittorrent == *b"BitTorrent protocol",
+            "peer did not answer with the BitTorrent protocol handshake"
+        );
+        anyhow::ensure!(
+            self.info_hash == info_hash,
+            "peer answered for info hash {} instead of {}",
+            hex::encode(self.info_hash),
+            hex::encode(info_hash)
+        );
+        Ok(())
+    }
+
+    /// Names of the protocol extensions advertised in the reserved bytes.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        [
+            (5, 0x10, "extension protocol"),
+            (7, 0x04, "fast"),
+            (7, 0x01, "dht"),
+        ]
+        .into_iter()
+        .filter(|&(byte, bit, _)| self.reserved[byte] & bit != 0)
+        .map(|(_, _, name)| name)
+        .collect()
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         unsafe { &mut *bytes }
@@ -254,6 +2118,11 @@ impl Request {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         unsafe { &mut *bytes }
     }
+
+    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
+        let data: &[u8; std::mem::size_of::<Self>()] = data.try_into().ok()?;
+        Some(unsafe { &*(data as *const [u8; std::mem::size_of::<Self>()] as *const Self) })
+    }
 }
 
 #[repr(C)]
@@ -302,6 +2171,13 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// Fast Extension (BEP 6) stand-in for a BitField with every piece set.
+    HaveAll = 14,
+    /// Fast Extension stand-in for a BitField with no piece set.
+    HaveNone = 15,
+    /// Fast Extension notice that a request will not be served.
+    RejectRequest = 16,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone)]
@@ -310,9 +2186,55 @@ pub struct Message {
     pub payload: Vec<u8>,
 }
 
-pub struct MessageFramer;
+pub struct MessageFramer {
+    /// Largest frame, tag included, either side may send.
+    max_frame: usize,
+    /// Largest block a Piece may carry or a Request may ask for.
+    max_block: usize,
+}
+
+impl MessageFramer {
+    const MAX: usize = 1 << 16;
 
-const MAX: usize = 1 << 16;
+    pub fn new(max_frame: usize, max_block: usize) -> Self {
+        Self {
+            max_frame,
+            max_block,
+        }
+    }
+
+    /// A framer that also accepts the BitField of a torrent with `pieces`
+    /// pieces, which outgrows the default limit for very large torrents.
+    pub fn for_pieces(pieces: usize) -> Self {
+        Self::new(
+            Self::MAX.max(1 + pieces.div_ceil(8)),
+            BLOCK_MAX_SIZE as usize,
+        )
+    }
+
+    fn check_block(&self, tag: MessageTag, payload: &[u8]) -> std::io::Result<()> {
+        let block = match tag {
+            MessageTag::Piece => payload.len().saturating_sub(8),
+            MessageTag::Request if payload.len() == 12 => {
+                u32::from_be_bytes(payload[8..].try_into().unwrap()) as usize
+            }
+            _ => return Ok(()),
+        };
+        if block > self.max_block {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Block of length {block} is too large."),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::new(Self::MAX, BLOCK_MAX_SIZE as usize)
+    }
+}
 
 impl Decoder for MessageFramer {
     type Item = Message;
@@ -336,7 +2258,7 @@ impl Decoder for MessageFramer {
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        if length > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length),
@@ -367,6 +2289,10 @@ impl Decoder for MessageFramer {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -380,24 +2306,143 @@ impl Decoder for MessageFramer {
             Vec::new()
         };
         src.advance(4 + length);
+        self.check_block(tag, &data)?;
 
         Ok(Some(Message { tag, payload: data }))
     }
 }
 
+#[test]
+fn large_bit_field_fits_frame_limit() {
+    let pieces = 600_000;
+    let mut frame = BytesMut::new();
+    MessageFramer::for_pieces(pieces)
+        .encode(
+            Message {
+                tag: MessageTag::BitField,
+                payload: vec![0xff; pieces / 8],
+            },
+            &mut frame,
+        )
+        .unwrap();
+
+    assert!(MessageFramer::default().decode(&mut frame.clone()).is_err());
+    let message = MessageFramer::for_pieces(pieces)
+        .decode(&mut frame)
+        .unwrap()
+        .expect("a whole frame");
+    assert_eq!(message.tag, MessageTag::BitField);
+    assert_eq!(message.payload.len(), pieces / 8);
+    assert!(frame.is_empty());
+}
+
+/// Decodes every whole frame in `buf`, leaving any trailing partial frame in
+/// place. Fails at the first malformed frame.
+pub fn decode_all(framer: &mut MessageFramer, buf: &mut BytesMut) -> std::io::Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    while let Some(message) = framer.decode(buf)? {
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+#[test]
+fn decode_all_concatenated() {
+    let mut framer = MessageFramer::default();
+    let mut buf = BytesMut::new();
+    let mut piece = Vec::from(&Request::new(1, 0, 0).as_bytes_mut()[..8]);
+    piece.extend_from_slice(b"data");
+    for (tag, payload) in [
+        (MessageTag::Choke, Vec::new()),
+        (MessageTag::UnChoke, Vec::new()),
+        (MessageTag::Piece, piece.clone()),
+    ] {
+        framer.encode(Message { tag, payload }, &mut buf).unwrap();
+    }
+    buf.extend_from_slice(&[0, 0, 0, 5, 4]);
+
+    let messages = decode_all(&mut framer, &mut buf).expect("well-formed frames");
+    let tags: Vec<_> = messages.iter().map(|m| m.tag).collect();
+    assert_eq!(
+        tags,
+        [MessageTag::Choke, MessageTag::UnChoke, MessageTag::Piece]
+    );
+    assert_eq!(messages[2].payload, piece);
+    assert_eq!(&buf[..], [0, 0, 0, 5, 4], "partial frame stays buffered");
+
+    // An unknown tag behind a good frame is an error, not the end.
+    let mut buf = BytesMut::new();
+    framer
+        .encode(
+            Message {
+                tag: MessageTag::Choke,
+                payload: Vec::new(),
+            },
+            &mut buf,
+        )
+        .unwrap();
+    buf.extend_from_slice(&[0, 0, 0, 1, 99]);
+    assert!(decode_all(&mut framer, &mut buf).is_err());
+}
+
+#[test]
+fn custom_frame_limit() {
+    let message = |len: usize| Message {
+        tag: MessageTag::BitField,
+        payload: vec![0xff; len],
+    };
+    let mut framer = MessageFramer::new(100, BLOCK_MAX_SIZE as usize);
+
+    let mut frame = BytesMut::new();
+    framer.encode(message(99), &mut frame).unwrap();
+    assert!(framer.encode(message(100), &mut BytesMut::new()).is_err());
+    let decoded = framer.decode(&mut frame).unwrap().expect("a whole frame");
+    assert_eq!(decoded.payload.len(), 99);
+
+    let mut frame = BytesMut::new();
+    MessageFramer::default()
+        .encode(message(100), &mut frame)
+        .unwrap();
+    assert!(framer.decode(&mut frame).is_err());
+}
+
+/// The message with no tag or payload, only a zero length, that keeps an
+/// idle connection open.
+pub struct KeepAlive;
+
+impl Encoder<KeepAlive> for MessageFramer {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&0u32.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[test]
+fn keep_alive_is_a_zero_length() {
+    let mut dst = BytesMut::new();
+    MessageFramer::default()
+        .encode(KeepAlive, &mut dst)
+        .expect("encode keep-alive");
+    assert_eq!(dst[..], [0, 0, 0, 0]);
+}
+
 impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a string if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX {
+        if item.payload.len() + 1 > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", item.payload.len()),
             ));
         }
 
+        self.check_block(item.tag, &item.payload)?;
+
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
         let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
@@ -1,6 +1,12 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    hash::{BuildHasher, Hasher},
+};
 
-use crate::{peer::Peer, torrent::Torrent};
+use crate::{
+    peer::{Peer, PeerStats},
+    torrent::Torrent,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Piece {
@@ -8,13 +14,21 @@ pub struct Piece {
     piece_i: u32,
     length: u32,
     hash: [u8; 20],
+    /// Random rank among pieces equally rare, so clients in the swarm do not
+    /// all start on the same piece.
+    tiebreak: u64,
 }
 
+/// Rarest first: the greatest piece, which a `BinaryHeap` pops first, is the
+/// one the fewest connected peers have. Pieces no peer has come last.
 impl Ord for Piece {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
+        other
+            .peers
+            .is_empty()
+            .cmp(&self.peers.is_empty())
+            .then(other.peers.len().cmp(&self.peers.len()))
+            .then(self.tiebreak.cmp(&other.tiebreak))
             .then(self.piece_i.cmp(&other.piece_i))
             .then(self.hash.cmp(&other.hash))
             .then(self.length.cmp(&other.length))
@@ -44,14 +58,34 @@ impl Piece {
             .filter_map(|(peer_i, peer)| peer.has_piece(piece_i as u32).then_some(peer_i))
             .collect();
 
+        Self::with_peers(piece_i as u32, piece_size as u32, piece_hash, peers)
+    }
+
+    fn with_peers(piece_i: u32, length: u32, hash: [u8; 20], peers: HashSet<usize>) -> Self {
+        let mut tiebreak = std::hash::RandomState::new().build_hasher();
+        tiebreak.write_u32(piece_i);
         Self {
             peers,
-            piece_i: piece_i as u32,
-            length: piece_size as u32,
-            hash: piece_hash,
+            piece_i,
+            length,
+            hash,
+            tiebreak: tiebreak.finish(),
         }
     }
 
+    /// Picks the best-scoring peer among those that have this piece.
+    pub(crate) fn preferred_peer<'a>(
+        &self,
+        stats: impl IntoIterator<Item = &'a PeerStats>,
+    ) -> Option<usize> {
+        stats
+            .into_iter()
+            .enumerate()
+            .filter(|(peer_i, _)| self.peers.contains(peer_i))
+            .max_by(|(_, a), (_, b)| a.score().total_cmp(&b.score()))
+            .map(|(peer_i, _)| peer_i)
+    }
+
     pub(crate) fn peers(&self) -> &HashSet<usize> {
         &self.peers
     }
@@ -60,11 +94,68 @@ impl Piece {
         self.piece_i
     }
 
-    pub(crate) fn hash(&self) -> &[u8; 20] {
-        &self.hash
+    /// The piece's expected SHA-1. Work items own it, so they do not borrow
+    /// the torrent and can move into spawned tasks.
+    pub(crate) fn hash(&self) -> [u8; 20] {
+        self.hash
     }
 
     pub(crate) fn length(&self) -> u32 {
         self.length
     }
 }
+
+#[test]
+fn prefers_fastest_peer() {
+    use std::time::Duration;
+
+    let piece = Piece::with_peers(0, 1 << 14, [0u8; 20], HashSet::from([0, 1, 2]));
+    let mut stats = vec![PeerStats::default(); 3];
+    stats[0].record_block(1 << 14, Duration::from_millis(400));
+    stats[1].record_block(1 << 14, Duration::from_millis(50));
+    stats[2].record_block(1 << 14, Duration::from_millis(200));
+
+    assert_eq!(piece.preferred_peer(&stats), Some(1));
+}
+
+#[tokio::test]
+async fn piece_hash_outlives_torrent() {
+    use sha1::{Digest, Sha1};
+
+    let piece = {
+        let t = crate::mock::torrent(4, b"0123456789");
+        Piece::new(1, &t, &[])
+    };
+    let hash = tokio::spawn(async move {
+        tokio::task::yield_now().await;
+        piece.hash()
+    })
+    .await
+    .unwrap();
+    let expected: [u8; 20] = Sha1::digest(b"4567").into();
+    assert_eq!(hash, expected);
+}
+
+#[test]
+fn rarest_pieces_come_first() {
+    use std::collections::BinaryHeap;
+
+    // The pieces each of three peers has.
+    let peer_pieces: [&[u32]; 3] = [&[0, 1, 2, 3], &[1, 2, 3], &[2, 3, 5]];
+    let mut heap: BinaryHeap<_> = (0..6)
+        .map(|piece_i| {
+            let peers = (0..peer_pieces.len())
+                .filter(|&peer_i| peer_pieces[peer_i].contains(&piece_i))
+                .collect();
+            Piece::with_peers(piece_i, 1 << 14, [0u8; 20], peers)
+        })
+        .collect();
+    let order: Vec<_> = std::iter::from_fn(|| heap.pop().map(|piece| piece.index())).collect();
+
+    // Pieces 0 and 5 have one peer each, then 1, then the common 2 and 3;
+    // nobody has 4.
+    let rank = |piece_i| order.iter().position(|&p| p == piece_i).unwrap();
+    assert!(rank(0).max(rank(5)) < rank(1), "{order:?}");
+    assert!(rank(1) < rank(2).min(rank(3)), "{order:?}");
+    assert_eq!(order[5], 4, "{order:?}");
+}
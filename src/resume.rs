@@ -0,0 +1,115 @@
+//! A small bencoded resume format:
+//!
+//! ```text
+//! d
+//!   5:files   l l<path components>e ... e
+//!   9:info-hash 20:<info hash>
+//!   6:pieces  <bitfield, high bit of the first byte is piece 0>
+//! e
+//! ```
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::torrent::Torrent;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resume {
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    /// Bitfield of pieces that are already downloaded and verified.
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub files: Vec<Vec<String>>,
+}
+
+impl Resume {
+    /// A resume state for `t` with no pieces completed.
+    pub fn new(t: &Torrent) -> Self {
+        Self {
+            info_hash: t.info_hash().to_vec(),
+            pieces: vec![0u8; t.info.pieces.0.len().div_ceil(8)],
+            files: t.files().into_iter().map(|f| f.path).collect(),
+        }
+    }
+
+    pub fn is_for(&self, t: &Torrent) -> bool {
+        self.info_hash == t.info_hash()
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        self.pieces
+            .get(piece_i / 8)
+            .is_some_and(|byte| byte & (0x80 >> (piece_i % 8)) != 0)
+    }
+
+    pub fn set_piece(&mut self, piece_i: usize) {
+        if let Some(byte) = self.pieces.get_mut(piece_i / 8) {
+            *byte |= 0x80 >> (piece_i % 8);
+        }
+    }
+}
+
+pub fn save_resume(path: impl AsRef<Path>, resume: &Resume) -> Result<()> {
+    let bytes = serde_bencode::to_bytes(resume).context("serialize resume state")?;
+    std::fs::write(path, bytes).context("write resume file")
+}
+
+pub fn load_resume(path: impl AsRef<Path>) -> Result<Resume> {
+    let bytes = std::fs::read(path).context("read resume file")?;
+    serde_bencode::from_bytes(&bytes).context("deserialize resume file")
+}
+
+/// Loads the resume state for `t` from `path`, starting fresh if the file is
+/// missing or belongs to another torrent.
+pub(crate) fn load_or_new(path: &Path, t: &Torrent) -> Result<Resume> {
+    if !path.exists() {
+        return Ok(Resume::new(t));
+    }
+    let resume = load_resume(path)?;
+    if resume.is_for(t) {
+        Ok(resume)
+    } else {
+        eprintln!(
+            "ignoring {}: it belongs to a different torrent",
+            path.display()
+        );
+        Ok(Resume::new(t))
+    }
+}
+
+#[tokio::test]
+async fn resume_skips_completed_pieces() {
+    let data: Vec<u8> = (0..4 * 1024).map(|i| (i % 97) as u8).collect();
+    let t = crate::mock::torrent(1024, &data);
+    let path = crate::mock::temp_path("state.resume");
+
+    let mut resume = Resume::new(&t);
+    resume.set_piece(0);
+    resume.set_piece(2);
+    save_resume(&path, &resume).unwrap();
+    assert_eq!(load_resume(&path).unwrap(), resume);
+
+    let seeders = [crate::mock::seeder(&t, data).await];
+    let config = crate::download::DownloadConfig {
+        resume_file: Some(path.clone()),
+        ..Default::default()
+    };
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(4);
+    crate::download::download_from_peers(&t, &seeders, &config, sink)
+        .await
+        .unwrap();
+
+    let mut fetched = Vec::new();
+    while let Some((piece_i, _)) = pieces.recv().await {
+        fetched.push(piece_i);
+    }
+    fetched.sort();
+    assert_eq!(fetched, vec![1, 3]);
+
+    let resume = load_resume(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!((0..4).all(|piece_i| resume.has_piece(piece_i)));
+}
@@ -0,0 +1,724 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, watch},
+};
+use tokio_util::{codec::Framed, task::AbortOnDropHandle};
+
+use crate::{
+    BLOCK_MAX_SIZE,
+    peer::{FAST_BIT, Handshake, Message, MessageFramer, MessageTag, PeerId, Request},
+    rate::RateLimiter,
+    torrent::Torrent,
+};
+
+#[derive(Debug, Clone)]
+pub struct SeedConfig {
+    /// Cap on outgoing piece data, in bytes per second.
+    pub max_upload_rate: Option<u64>,
+    /// How often the unchoked set is rotated among interested peers.
+    pub rechoke_interval: Duration,
+    /// How many peers are unchoked at once.
+    pub upload_slots: usize,
+    /// Ports to listen on, the first free one is taken; see [`listen`].
+    pub port_range: Option<RangeInclusive<u16>>,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        Self {
+            max_upload_rate: None,
+            rechoke_interval: Duration::from_secs(10),
+            upload_slots: 4,
+            port_range: None,
+        }
+    }
+}
+
+/// Listens for peers on the first free port of `config.port_range`, or on
+/// `port` without a range. Announce the listener's port, which is the one
+/// actually bound.
+pub async fn listen(config: &SeedConfig, port: u16) -> Result<TcpListener> {
+    let Some(range) = &config.port_range else {
+        return TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+            .await
+            .with_context(|| format!("listen on port {port}"));
+    };
+    for port in range.clone() {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e).with_context(|| format!("listen on port {port}")),
+        }
+    }
+    anyhow::bail!(
+        "every port from {} to {} is in use",
+        range.start(),
+        range.end()
+    )
+}
+
+#[tokio::test]
+async fn listen_skips_taken_ports() {
+    let taken = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+    let first = taken.local_addr().unwrap().port();
+    let range = first..=first.saturating_add(16);
+    let config = SeedConfig {
+        port_range: Some(range.clone()),
+        ..Default::default()
+    };
+    let listener = listen(&config, 6881).await.expect("a free port in range");
+    let port = listener.local_addr().unwrap().port();
+    assert!(port != first && range.contains(&port), "bound {port}");
+
+    let config = SeedConfig {
+        port_range: Some(first..=first),
+        ..Default::default()
+    };
+    let err = listen(&config, 6881).await.expect_err("only a taken port");
+    assert!(err.to_string().contains("in use"), "{err}");
+}
+
+/// Source of the data we serve to other peers.
+pub trait PieceStore: Send + Sync + 'static {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>>;
+}
+
+/// A [`PieceStore`] over the whole torrent held in memory.
+pub struct MemoryStore {
+    data: Vec<u8>,
+    piece_length: usize,
+}
+
+impl MemoryStore {
+    pub fn new(data: Vec<u8>, piece_length: usize) -> Self {
+        Self { data, piece_length }
+    }
+}
+
+impl PieceStore for MemoryStore {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let start = (index as usize)
+            .checked_mul(self.piece_length)
+            .and_then(|start| start.checked_add(begin as usize))
+            .context("requested block offset overflows")?;
+        self.data
+            .get(start..start.saturating_add(length as usize))
+            .map(<[u8]>::to_vec)
+            .context("requested block is out of range")
+    }
+}
+
+/// A [`PieceStore`] reading from the torrent's data laid out in one file.
+pub struct FileStore {
+    file: std::sync::Mutex<std::fs::File>,
+    piece_length: usize,
+}
+
+impl FileStore {
+    pub fn open(path: &std::path::Path, piece_length: usize) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+        Ok(Self {
+            file: std::sync::Mutex::new(file),
+            piece_length,
+        })
+    }
+}
+
+impl PieceStore for FileStore {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let start = index as u64 * self.piece_length as u64 + begin as u64;
+        let mut block = vec![0u8; length as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(start))
+            .context("seek to requested block")?;
+        file.read_exact(&mut block)
+            .context("requested block is out of range")?;
+        Ok(block)
+    }
+}
+
+/// Most requests one peer may have waiting to be served.
+const MAX_PENDING_REQUESTS: usize = 250;
+
+/// Serves a complete torrent to every peer that connects.
+#[derive(Clone)]
+pub struct Seeder {
+    info_hash: [u8; 20],
+    pieces: usize,
+    store: Arc<dyn PieceStore>,
+    upload_limit: Option<Arc<Mutex<RateLimiter>>>,
+    rechoke_interval: Duration,
+    upload_slots: usize,
+    choker: Arc<std::sync::Mutex<Choker>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Seeder {
+    pub fn new(t: &Torrent, store: impl PieceStore, config: &SeedConfig) -> Self {
+        Self {
+            info_hash: t.info_hash(),
+            pieces: t.info.pieces.0.len(),
+            store: Arc::new(store),
+            upload_limit: config
+                .max_upload_rate
+                .map(|rate| Arc::new(Mutex::new(RateLimiter::new(rate, BLOCK_MAX_SIZE as u64)))),
+            rechoke_interval: config.rechoke_interval,
+            upload_slots: config.upload_slots,
+            choker: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+
+    /// Accepts peers from `listener` until it fails, serving each on its own task.
+    pub async fn run(self, listener: TcpListener) -> Result<()> {
+        let choker = self.choker.clone();
+        let (interval, slots) = (self.rechoke_interval, self.upload_slots);
+        let _rechoke = AbortOnDropHandle::new(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                choker.lock().unwrap().rechoke(slots);
+            }
+        }));
+
+        loop {
+            let (stream, addr) = listener.accept().await.context("accept peer")?;
+            let seeder = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = seeder.serve(stream).await {
+                    eprintln!("stopped seeding to {addr}: {e:?}");
+                }
+            });
+        }
+    }
+
+    async fn serve(self, mut stream: TcpStream) -> Result<()> {
+        let mut handshake = Handshake::new([0u8; 20], [0u8; 20]);
+        stream
+            .read_exact(handshake.as_bytes_mut())
+            .await
+            .context("read handshake")?;
+        anyhow::ensure!(
+            handshake.length == 19 && handshake.bittorrent == *b"BitTorrent protocol",
+            "peer did not send a BitTorrent protocol handshake"
+        );
+        anyhow::ensure!(
+            handshake.info_hash == self.info_hash,
+            "peer asked for a torrent we are not seeding"
+        );
+        let fast = handshake.reserved[7] & FAST_BIT != 0;
+        let mut reply = Handshake::new(self.info_hash, *PeerId::session().as_bytes());
+        if fast {
+            reply.reserved[7] |= FAST_BIT;
+        }
+        stream
+            .write_all(reply.as_bytes_mut())
+            .await
+            .context("write handshake")?;
+
+        let mut stream = Framed::new(stream, MessageFramer::for_pieces(self.pieces));
+        // We have every piece, which peers that know the Fast Extension
+        // understand without a full BitField.
+        let have = if fast {
+            Message {
+                tag: MessageTag::HaveAll,
+                payload: Vec::new(),
+            }
+        } else {
+            let mut bit_field = vec![0u8; self.pieces.div_ceil(8)];
+            for piece_i in 0..self.pieces {
+                bit_field[piece_i / 8] |= 0x80 >> (piece_i % 8);
+            }
+            Message {
+                tag: MessageTag::BitField,
+                payload: bit_field,
+            }
+        };
+        stream.send(have).await.context("send our pieces")?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut unchoke = self.choker.lock().unwrap().join(id);
+        let _slot = SlotGuard {
+            choker: self.choker.clone(),
+            id,
+        };
+
+        let mut choked = true;
+        // Requests not served yet, as their raw payloads, which a Cancel
+        // repeats exactly.
+        let mut pending = VecDeque::new();
+        loop {
+            // Messages already received go first, so a Cancel that arrived
+            // behind its Request is seen before the block is sent.
+            tokio::select! {
+                biased;
+                changed = unchoke.changed() => {
+                    changed.expect("choker keeps the sender until the slot is dropped");
+                    let unchoked = *unchoke.borrow_and_update();
+                    if unchoked == choked {
+                        choked = !unchoked;
+                        let tag = if choked { MessageTag::Choke } else { MessageTag::UnChoke };
+                        stream
+                            .send(Message { tag, payload: Vec::new() })
+                            .await
+                            .context("send choke state")?;
+                        if choked && fast {
+                            // Choking discards the peer's requests, which a
+                            // Fast Extension peer is told about one by one.
+                            for request in pending.drain(..) {
+                                stream
+                                    .feed(Message {
+                                        tag: MessageTag::RejectRequest,
+                                        payload: request,
+                                    })
+                                    .await
+                                    .context("reject request")?;
+                            }
+                            SinkExt::<Message>::flush(&mut stream)
+                                .await
+                                .context("reject requests")?;
+                        } else if choked {
+                            pending.clear();
+                        }
+                    }
+                }
+                message = stream.next() => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    let message = message.context("read message")?;
+                    if let Some(reply) = self.handle(message, id, choked, fast, &mut pending)? {
+                        stream.send(reply).await.context("reply to peer")?;
+                    }
+                }
+                _ = std::future::ready(()), if !pending.is_empty() => {
+                    let request = pending.pop_front().expect("pending is not empty");
+                    self.serve_request(&mut stream, request).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        message: Message,
+        id: u64,
+        choked: bool,
+        fast: bool,
+        pending: &mut VecDeque<Vec<u8>>,
+    ) -> Result<Option<Message>> {
+        match message.tag {
+            MessageTag::Interested => {
+                let mut choker = self.choker.lock().unwrap();
+                choker.set_interested(id, true, self.upload_slots);
+            }
+            MessageTag::NotInterested => {
+                let mut choker = self.choker.lock().unwrap();
+                choker.set_interested(id, false, self.upload_slots);
+            }
+            MessageTag::Request if !choked => {
+                let request =
+                    Request::ref_from_bytes(&message.payload).context("malformed request")?;
+                anyhow::ensure!(
+                    request.length() <= BLOCK_MAX_SIZE,
+                    "requested block of {} bytes is too large",
+                    request.length()
+                );
+                anyhow::ensure!(
+                    pending.len() < MAX_PENDING_REQUESTS,
+                    "peer queued more than {MAX_PENDING_REQUESTS} requests"
+                );
+                pending.push_back(message.payload);
+            }
+            // Requests while choked are dropped, and a Fast Extension peer
+            // expects to hear so.
+            MessageTag::Request if fast => {
+                return Ok(Some(Message {
+                    tag: MessageTag::RejectRequest,
+                    payload: message.payload,
+                }));
+            }
+            MessageTag::Cancel => {
+                // The block may have gone out already; then there is nothing
+                // left to cancel.
+                if let Some(at) = pending
+                    .iter()
+                    .position(|request| *request == message.payload)
+                {
+                    pending.remove(at);
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    async fn serve_request(
+        &self,
+        stream: &mut Framed<TcpStream, MessageFramer>,
+        request: Vec<u8>,
+    ) -> Result<()> {
+        let parsed = Request::ref_from_bytes(&request).expect("checked when queued");
+        let block = self
+            .store
+            .read_block(parsed.index(), parsed.begin(), parsed.length())?;
+        if let Some(limit) = &self.upload_limit {
+            limit.lock().await.acquire(block.len()).await;
+        }
+
+        let mut payload = request[..8].to_vec();
+        payload.extend_from_slice(&block);
+        stream
+            .send(Message {
+                tag: MessageTag::Piece,
+                payload,
+            })
+            .await
+            .context("send piece")
+    }
+}
+
+/// Decides which interested peers hold the upload slots.
+#[derive(Debug, Default)]
+struct Choker {
+    peers: Vec<Slot>,
+    /// Rotates which interested peers get the slots on each rechoke.
+    cursor: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+    id: u64,
+    interested: bool,
+    unchoked: watch::Sender<bool>,
+}
+
+impl Choker {
+    fn join(&mut self, id: u64) -> watch::Receiver<bool> {
+        let (unchoked, receiver) = watch::channel(false);
+        self.peers.push(Slot {
+            id,
+            interested: false,
+            unchoked,
+        });
+        receiver
+    }
+
+    fn leave(&mut self, id: u64) {
+        self.peers.retain(|slot| slot.id != id);
+    }
+
+    /// Records interest, handing out a free slot right away rather than
+    /// waiting for the next rechoke.
+    fn set_interested(&mut self, id: u64, interested: bool, slots: usize) {
+        let free = self.unchoked() < slots;
+        let Some(slot) = self.peers.iter_mut().find(|slot| slot.id == id) else {
+            return;
+        };
+        slot.interested = interested;
+        if !interested || free {
+            slot.unchoked.send_replace(interested);
+        }
+    }
+
+    fn rechoke(&mut self, slots: usize) {
+        let interested: Vec<_> = (0..self.peers.len())
+            .filter(|&i| self.peers[i].interested)
+            .collect();
+        if interested.is_empty() {
+            return;
+        }
+
+        self.cursor %= interested.len();
+        let chosen: HashSet<_> = (0..slots.min(interested.len()))
+            .map(|k| interested[(self.cursor + k) % interested.len()])
+            .collect();
+        for (i, slot) in self.peers.iter().enumerate() {
+            let unchoke = chosen.contains(&i);
+            slot.unchoked.send_if_modified(|unchoked| {
+                let changed = *unchoked != unchoke;
+                *unchoked = unchoke;
+                changed
+            });
+        }
+        self.cursor += 1;
+    }
+
+    fn unchoked(&self) -> usize {
+        self.peers
+            .iter()
+            .filter(|slot| *slot.unchoked.borrow())
+            .count()
+    }
+}
+
+/// Releases a peer's slot when its connection ends.
+struct SlotGuard {
+    choker: Arc<std::sync::Mutex<Choker>>,
+    id: u64,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.choker.lock().unwrap().leave(self.id);
+    }
+}
+
+#[test]
+fn rechoke_rotates_upload_slots() {
+    let mut choker = Choker::default();
+    let receivers: Vec<_> = (0..3).map(|id| choker.join(id)).collect();
+    for id in 0..3 {
+        choker.set_interested(id, true, 2);
+    }
+
+    let unchoked = || -> Vec<bool> { receivers.iter().map(|r| *r.borrow()).collect() };
+    assert_eq!(unchoked(), [true, true, false]);
+
+    let mut seen = [false; 3];
+    for _ in 0..3 {
+        choker.rechoke(2);
+        let state = unchoked();
+        assert_eq!(state.iter().filter(|&&u| u).count(), 2);
+        for (seen, unchoked) in seen.iter_mut().zip(state) {
+            *seen |= unchoked;
+        }
+    }
+    assert_eq!(seen, [true; 3], "every interested peer gets a turn");
+}
+
+#[tokio::test]
+async fn upload_rate_is_capped() {
+    let data: Vec<u8> = (0..8 * BLOCK_MAX_SIZE).map(|i| (i % 13) as u8).collect();
+    let t = crate::mock::torrent(4 * BLOCK_MAX_SIZE as usize, &data);
+    let (listener, addr) = crate::mock::listen().await;
+    let config = SeedConfig {
+        max_upload_rate: Some(8 * BLOCK_MAX_SIZE as u64),
+        ..Default::default()
+    };
+    let seeder = Seeder::new(&t, MemoryStore::new(data, t.info.piece_length), &config);
+    tokio::spawn(seeder.run(listener));
+
+    let start = std::time::Instant::now();
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(2);
+    crate::download::download_from_peers(
+        &t,
+        &[addr],
+        &crate::download::DownloadConfig::default(),
+        sink,
+    )
+    .await
+    .expect("download from rate limited seeder");
+    assert!(pieces.recv().await.is_some());
+
+    // One block fits in the initial burst; the other seven wait for tokens.
+    let minimum = std::time::Duration::from_secs_f64(7.0 / 8.0);
+    assert!(start.elapsed() >= minimum, "took {:?}", start.elapsed());
+}
+
+#[tokio::test]
+async fn download_from_seeded_file() {
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i % 239) as u8).collect();
+    let t = crate::mock::torrent(1 << 15, &data);
+    let path = crate::mock::temp_path("seeded.bin");
+    std::fs::write(&path, &data).unwrap();
+
+    let store = FileStore::open(&path, t.info.piece_length).unwrap();
+    let (listener, addr) = crate::mock::listen().await;
+    tokio::spawn(Seeder::new(&t, store, &SeedConfig::default()).run(listener));
+
+    let (peers, config) = ([addr], crate::download::DownloadConfig::default());
+    let (sink, mut pieces) = tokio::sync::mpsc::channel(4);
+    let (downloaded, ()) = tokio::join!(
+        crate::download::download_from_peers(&t, &peers, &config, sink),
+        async {
+            while let Some((piece_i, bytes)) = pieces.recv().await {
+                let start = piece_i * t.info.piece_length;
+                assert_eq!(bytes, data[start..][..bytes.len()]);
+            }
+        }
+    );
+    downloaded.expect("download from seeded file");
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn fast_peers_get_have_all() {
+    let data = vec![7u8; 3 * BLOCK_MAX_SIZE as usize];
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let addr = crate::mock::seeder(&t, data).await;
+
+    for (reserved, expected) in [(FAST_BIT, MessageTag::HaveAll), (0, MessageTag::BitField)] {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut handshake = Handshake::new(t.info_hash(), *b"-TEST00-000000000000");
+        handshake.reserved[7] = reserved;
+        stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+        stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+        assert_eq!(handshake.reserved[7] & FAST_BIT, reserved);
+
+        let mut stream = Framed::new(stream, MessageFramer::for_pieces(3));
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.tag, expected);
+        if expected == MessageTag::BitField {
+            assert_eq!(first.payload, [0xe0]);
+        } else {
+            assert!(first.payload.is_empty());
+        }
+    }
+}
+
+#[tokio::test]
+async fn fast_peers_hear_about_dropped_requests() {
+    let data = vec![3u8; BLOCK_MAX_SIZE as usize];
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let addr = crate::mock::seeder(&t, data).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut handshake = Handshake::new(t.info_hash(), *b"-TEST00-000000000000");
+    handshake.reserved[7] = FAST_BIT;
+    stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+    stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::for_pieces(1));
+    let have_all = stream.next().await.unwrap().unwrap();
+    assert_eq!(have_all.tag, MessageTag::HaveAll);
+
+    let message = |tag, begin| Message {
+        tag,
+        payload: Vec::from(Request::new(0, begin, 16).as_bytes_mut()),
+    };
+    // Still choked, so this one is rejected.
+    stream.send(message(MessageTag::Request, 0)).await.unwrap();
+    let rejected = stream.next().await.unwrap().unwrap();
+    assert_eq!(rejected.tag, MessageTag::RejectRequest);
+    assert_eq!(rejected.payload, message(MessageTag::Request, 0).payload);
+
+    stream
+        .send(Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        })
+        .await
+        .unwrap();
+    let unchoke = stream.next().await.unwrap().unwrap();
+    assert_eq!(unchoke.tag, MessageTag::UnChoke);
+
+    // Sent together, so the choke lands before the request is served.
+    stream.feed(message(MessageTag::Request, 32)).await.unwrap();
+    stream
+        .feed(Message {
+            tag: MessageTag::NotInterested,
+            payload: Vec::new(),
+        })
+        .await
+        .unwrap();
+    SinkExt::<Message>::flush(&mut stream).await.unwrap();
+    let choke = stream.next().await.unwrap().unwrap();
+    assert_eq!(choke.tag, MessageTag::Choke);
+    let rejected = stream.next().await.unwrap().unwrap();
+    assert_eq!(rejected.tag, MessageTag::RejectRequest);
+    assert_eq!(rejected.payload, message(MessageTag::Request, 32).payload);
+}
+
+#[tokio::test]
+async fn request_is_answered_once_unchoked() {
+    let data: Vec<u8> = (0..2 * BLOCK_MAX_SIZE).map(|i| (i % 239) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let addr = crate::mock::seeder(&t, data.clone()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut handshake = Handshake::new(t.info_hash(), *b"-TEST00-000000000000");
+    stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+    stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::for_pieces(2));
+    let bit_field = stream.next().await.unwrap().unwrap();
+    assert_eq!(bit_field.tag, MessageTag::BitField);
+
+    let request = |index, begin, length| Message {
+        tag: MessageTag::Request,
+        payload: Vec::from(Request::new(index, begin, length).as_bytes_mut()),
+    };
+    // Still choked, so this one goes unanswered.
+    stream.send(request(0, 0, 16)).await.unwrap();
+    stream
+        .send(Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        })
+        .await
+        .unwrap();
+    let unchoke = stream.next().await.unwrap().unwrap();
+    assert_eq!(unchoke.tag, MessageTag::UnChoke);
+
+    stream.send(request(1, 100, 32)).await.unwrap();
+    let piece = stream.next().await.unwrap().unwrap();
+    assert_eq!(piece.tag, MessageTag::Piece);
+    assert_eq!(piece.payload[..4], 1u32.to_be_bytes());
+    assert_eq!(piece.payload[4..8], 100u32.to_be_bytes());
+    let start = BLOCK_MAX_SIZE as usize + 100;
+    assert_eq!(piece.payload[8..], data[start..start + 32]);
+}
+
+#[tokio::test]
+async fn cancelled_request_is_not_served() {
+    let data: Vec<u8> = (0..BLOCK_MAX_SIZE).map(|i| (i % 233) as u8).collect();
+    let t = crate::mock::torrent(BLOCK_MAX_SIZE as usize, &data);
+    let addr = crate::mock::seeder(&t, data.clone()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut handshake = Handshake::new(t.info_hash(), *b"-TEST00-000000000000");
+    stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+    stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::for_pieces(1));
+    stream.next().await.unwrap().unwrap();
+    stream
+        .send(Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        })
+        .await
+        .unwrap();
+    let unchoke = stream.next().await.unwrap().unwrap();
+    assert_eq!(unchoke.tag, MessageTag::UnChoke);
+
+    let message = |tag, begin| Message {
+        tag,
+        payload: Vec::from(Request::new(0, begin, 16).as_bytes_mut()),
+    };
+    // Sent together, so the cancel is read before the request is served.
+    stream.feed(message(MessageTag::Request, 0)).await.unwrap();
+    stream.feed(message(MessageTag::Cancel, 0)).await.unwrap();
+    // Cancels nothing, and is ignored.
+    stream.feed(message(MessageTag::Cancel, 64)).await.unwrap();
+    stream.feed(message(MessageTag::Request, 32)).await.unwrap();
+    SinkExt::<Message>::flush(&mut stream).await.unwrap();
+
+    let piece = stream.next().await.unwrap().unwrap();
+    assert_eq!(piece.tag, MessageTag::Piece);
+    assert_eq!(piece.payload[4..8], 32u32.to_be_bytes());
+    assert_eq!(piece.payload[8..], data[32..48]);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), stream.next())
+            .await
+            .is_err(),
+        "the cancelled block is never sent"
+    );
+}
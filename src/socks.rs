@@ -0,0 +1,151 @@
+//! Just enough of a SOCKS5 (RFC 1928) client to tunnel peer connections,
+//! with the username/password method from RFC 1929.
+
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result, bail, ensure};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[derive(Debug, Clone)]
+pub struct Socks5Config {
+    /// Address of the SOCKS5 proxy.
+    pub addr: SocketAddr,
+    /// Username and password, if the proxy requires them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Config {
+    /// The proxy as a URL for reqwest. `socks5h` lets the proxy resolve
+    /// tracker hostnames, so DNS lookups do not leak around the tunnel.
+    /// Credentials are percent-encoded, so any characters survive.
+    pub(crate) fn url(&self) -> reqwest::Url {
+        let mut url = reqwest::Url::parse(&format!("socks5h://{}", self.addr))
+            .expect("socket address makes a valid URL");
+        if let Some((user, pass)) = &self.credentials {
+            // The setters encode everything but `%`, which they take as the
+            // start of an escape already made.
+            let (user, pass) = (user.replace('%', "%25"), pass.replace('%', "%25"));
+            url.set_username(&user).expect("URL has a host");
+            url.set_password(Some(&pass)).expect("URL has a host");
+        }
+        url
+    }
+}
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Asks the proxy behind `stream` to connect to `target`, leaving `stream` as
+/// a tunnel to it.
+pub(crate) async fn handshake(
+    stream: &mut TcpStream,
+    config: &Socks5Config,
+    target: SocketAddr,
+) -> Result<()> {
+    let method = if config.credentials.is_some() {
+        USER_PASS
+    } else {
+        NO_AUTH
+    };
+    stream
+        .write_all(&[VERSION, 1, method])
+        .await
+        .context("send SOCKS5 greeting")?;
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .context("read SOCKS5 greeting")?;
+    ensure!(reply[0] == VERSION, "proxy does not speak SOCKS5");
+    ensure!(
+        reply[1] == method,
+        "proxy refused SOCKS5 authentication method {method}"
+    );
+
+    if let Some((user, pass)) = &config.credentials {
+        ensure!(
+            user.len() <= 255 && pass.len() <= 255,
+            "SOCKS5 credentials are longer than 255 bytes"
+        );
+        let mut auth = vec![1, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(pass.len() as u8);
+        auth.extend_from_slice(pass.as_bytes());
+        stream
+            .write_all(&auth)
+            .await
+            .context("send SOCKS5 credentials")?;
+        stream
+            .read_exact(&mut reply)
+            .await
+            .context("read SOCKS5 authentication status")?;
+        ensure!(reply[1] == 0, "proxy rejected the SOCKS5 credentials");
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target {
+        SocketAddr::V4(target) => {
+            request.push(IPV4);
+            request.extend_from_slice(&target.ip().octets());
+        }
+        SocketAddr::V6(target) => {
+            request.push(IPV6);
+            request.extend_from_slice(&target.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream
+        .write_all(&request)
+        .await
+        .context("send SOCKS5 connect")?;
+
+    let mut reply = [0u8; 4];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .context("read SOCKS5 connect reply")?;
+    ensure!(reply[0] == VERSION, "proxy does not speak SOCKS5");
+    if reply[1] != 0 {
+        bail!(
+            "proxy could not connect to {target}: SOCKS5 error {}",
+            reply[1]
+        );
+    }
+    // Skip the address the proxy bound for us.
+    let bound = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => stream
+            .read_u8()
+            .await
+            .context("read SOCKS5 bound address")? as usize,
+        atyp => bail!("unknown SOCKS5 address type {atyp}"),
+    };
+    let mut bound = vec![0u8; bound + 2];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .context("read SOCKS5 bound address")?;
+    Ok(())
+}
+
+#[test]
+fn proxy_url_encodes_credentials() {
+    let config = Socks5Config {
+        addr: "127.0.0.1:1080".parse().unwrap(),
+        credentials: Some((String::from("us@r:1"), String::from("p@ss:w/o%rd"))),
+    };
+    let url = reqwest::Url::parse(config.url().as_str()).unwrap();
+    assert_eq!(url.host_str(), Some("127.0.0.1"));
+    assert_eq!(url.port(), Some(1080));
+    assert_eq!(url.username(), "us%40r%3A1");
+    assert_eq!(url.password(), Some("p%40ss%3Aw%2Fo%25rd"));
+}
@@ -0,0 +1,70 @@
+//! Checking for enough free disk space before a download starts.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::torrent::Torrent;
+
+/// Fails if the volume holding `dir` has less free space than `t` needs.
+/// Platforms where free space cannot be queried pass the check.
+pub fn check_space(t: &Torrent, dir: &Path) -> Result<()> {
+    check_space_with(t, dir, available_space)
+}
+
+fn check_space_with(
+    t: &Torrent,
+    dir: &Path,
+    available: impl FnOnce(&Path) -> std::io::Result<Option<u64>>,
+) -> Result<()> {
+    let needed = t.length() as u64;
+    let Some(available) =
+        available(dir).with_context(|| format!("query free space of {}", dir.display()))?
+    else {
+        return Ok(());
+    };
+    anyhow::ensure!(
+        available >= needed,
+        "torrent needs {needed} bytes but only {available} are free on {}",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Bytes available to us on the volume holding `path`, if the platform can
+/// tell.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read on success.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[test]
+fn too_little_space_is_an_error() {
+    let t = crate::mock::torrent(1024, &[0u8; 5000]);
+    let dir = Path::new("/downloads");
+    let err = check_space_with(&t, dir, |_| Ok(Some(4999))).unwrap_err();
+    assert!(err.to_string().contains("5000 bytes"), "{err}");
+    check_space_with(&t, dir, |_| Ok(Some(5000))).expect("exactly enough");
+    check_space_with(&t, dir, |_| Ok(None)).expect("unknown free space");
+
+    #[cfg(unix)]
+    assert!(available_space(&std::env::temp_dir()).unwrap().is_some());
+}
@@ -0,0 +1,63 @@
+//! A machine-readable status file, so another process can follow a download.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Progress of a download as written to [`crate::download::DownloadConfig::status_file`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DownloadStatus {
+    pub pieces_complete: usize,
+    pub pieces_total: usize,
+    pub bytes_downloaded: usize,
+    /// Average download rate since the start, in bytes per second.
+    pub download_rate: f64,
+    pub connected_peers: usize,
+}
+
+/// Writes `status` as JSON to a temporary file next to `path` and renames it
+/// over `path`, so readers never see a partial file.
+pub fn write_status(path: &Path, status: &DownloadStatus) -> Result<()> {
+    let json = serde_json::to_vec_pretty(status).context("serialize download status")?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, json).with_context(|| format!("write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("replace {}", path.display()))
+}
+
+pub fn read_status(path: &Path) -> Result<DownloadStatus> {
+    let json = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    serde_json::from_slice(&json).context("deserialize download status")
+}
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes the latest status to `path` every second, and once more when the
+/// sender of `status` is dropped.
+pub(crate) async fn keep_written(path: PathBuf, mut status: watch::Receiver<DownloadStatus>) {
+    let mut ticks = tokio::time::interval(UPDATE_INTERVAL);
+    loop {
+        let done = tokio::select! {
+            _ = ticks.tick() => false,
+            changed = status.changed() => {
+                if changed.is_ok() {
+                    continue;
+                }
+                true
+            }
+        };
+        let latest = status.borrow_and_update().clone();
+        if let Err(e) = write_status(&path, &latest) {
+            eprintln!("Failed to write status file: {e:#}");
+        }
+        if done {
+            break;
+        }
+    }
+}
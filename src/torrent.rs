@@ -1,14 +1,20 @@
-use std::path::Path;
+use std::{net::SocketAddr, path::Path};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize, de::Visitor};
 use sha1::{Digest, Sha1};
 
-use crate::download::Downloaded;
+use crate::{
+    BLOCK_MAX_SIZE,
+    download::{DownloadConfig, Downloaded},
+};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct Torrent {
     pub announce: String, //reqwest::Url,
+    /// Tiers of tracker URLs (BEP 12), preferred over `announce` when present.
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: Info,
 }
 
@@ -22,9 +28,29 @@ impl Torrent {
 
     pub async fn read(file: impl AsRef<Path>) -> Result<Self> {
         let torrent = tokio::fs::read(file).await.context("read torrent file")?;
-        let t: Torrent = serde_bencode::from_bytes(&torrent).context("deserialize torrent file")?;
+        Self::from_bytes(&torrent)
+    }
+
+    /// Parses a `.torrent` file. A file that ends in the middle of a value is
+    /// reported as truncated, with the offset where parsing ran out.
+    pub fn from_bytes(dot_torrent: &[u8]) -> Result<Self> {
+        if let Some(offset) = truncated_at(dot_torrent) {
+            anyhow::bail!(
+                "torrent file appears truncated: parsing failed at byte {offset} of {}",
+                dot_torrent.len()
+            );
+        }
+        serde_bencode::from_bytes(dot_torrent).context("deserialize torrent file")
+    }
 
-        Ok(t)
+    /// Like [`Torrent::read`], but parses straight from a memory map of the
+    /// file instead of reading it into a buffer first.
+    pub fn read_mapped(file: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::File::open(file).context("open torrent file")?;
+        // SAFETY: the map only lives while parsing, and the torrent file is
+        // not expected to be truncated or rewritten meanwhile.
+        let map = unsafe { memmap2::Mmap::map(&file) }.context("map torrent file")?;
+        Self::from_bytes(&map)
     }
 
     pub fn print_tree(&self) {
@@ -41,18 +67,217 @@ impl Torrent {
     }
 
     pub fn length(&self) -> usize {
+        self.info.length()
+    }
+
+    /// Indices of the pieces holding any of the bytes `start..end` of the
+    /// torrent's data, clamped to the pieces that exist.
+    pub fn pieces_overlapping(&self, start: u64, end: u64) -> std::ops::Range<usize> {
+        let piece_length = self.info.piece_length as u64;
+        let end = end.min(self.length() as u64);
+        if start >= end {
+            return 0..0;
+        }
+        (start / piece_length) as usize..end.div_ceil(piece_length) as usize
+    }
+
+    /// The piece holding byte `file_offset` of file `file_index` (counted as
+    /// in [`Torrent::files`]), and the begin of the block it falls in. `None`
+    /// if there is no such file or the offset is past its end.
+    pub fn locate(&self, file_index: usize, file_offset: u64) -> Option<(usize, u32)> {
+        let files = self.files();
+        let file = files.get(file_index)?;
+        if file_offset >= file.length as u64 {
+            return None;
+        }
+        let start: u64 = files[..file_index].iter().map(|f| f.length as u64).sum();
+        let offset = start + file_offset;
+        let piece_length = self.info.piece_length as u64;
+        let begin = (offset % piece_length) as u32;
+        Some((
+            (offset / piece_length) as usize,
+            begin - begin % BLOCK_MAX_SIZE,
+        ))
+    }
+
+    /// The tiers of `announce_list` (BEP 12), or a single tier of just
+    /// `announce` without one.
+    pub fn tracker_tiers(&self) -> crate::tracker::TrackerTiers {
+        let tiers = match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        };
+        crate::tracker::TrackerTiers::new(tiers)
+    }
+
+    /// Every tracker URL in preference order: the tiers of `announce_list`
+    /// flattened without duplicates, or just `announce` without one.
+    pub fn trackers(&self) -> Vec<String> {
+        let Some(tiers) = &self.announce_list else {
+            return vec![self.announce.clone()];
+        };
+        let mut trackers: Vec<String> = Vec::new();
+        for tracker in tiers.iter().flatten() {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+
+    /// A `magnet:` URI for the torrent, naming it and listing every tracker.
+    pub fn to_magnet(&self) -> String {
+        let mut params = vec![("dn", self.info.name.clone())];
+        params.extend(self.trackers().into_iter().map(|tracker| ("tr", tracker)));
+        let params = serde_urlencoded::to_string(params).expect("encode magnet parameters");
+        format!(
+            "magnet:?xt=urn:btih:{}&{params}",
+            hex::encode(self.info_hash())
+        )
+    }
+
+    /// The tracker as `scheme://host:port`, without path or query, for
+    /// display. The port is left out when the URL has none and the scheme has
+    /// no default.
+    pub fn announce_host(&self) -> Option<String> {
+        let url = reqwest::Url::parse(&self.announce).ok()?;
+        let host = url.host_str()?;
+        Some(match url.port_or_known_default() {
+            Some(port) => format!("{}://{host}:{port}", url.scheme()),
+            None => format!("{}://{host}", url.scheme()),
+        })
+    }
+
+    /// Checks that the sizes in the torrent are consistent and small enough
+    /// for the wire protocol, whose piece indices and in-piece offsets are
+    /// `u32`, so a crafted torrent is rejected instead of overflowing later.
+    pub fn validate(&self) -> Result<()> {
+        if let Keys::MultiFile { ref files } = self.info.keys {
+            for file in files {
+                file.relative_path()?;
+            }
+        }
+        let length = match self.info.keys {
+            Keys::SingleFile { length } => Some(length),
+            Keys::MultiFile { ref files } => files
+                .iter()
+                .try_fold(0usize, |total, f| total.checked_add(f.length)),
+        }
+        .context("total length overflows")?;
+
+        let piece_length = self.info.piece_length;
+        anyhow::ensure!(piece_length > 0, "piece length is zero");
+        anyhow::ensure!(
+            u32::try_from(piece_length).is_ok(),
+            "piece length {piece_length} does not fit in a u32 offset"
+        );
+        let pieces = self.info.pieces.0.len();
+        anyhow::ensure!(
+            u32::try_from(pieces).is_ok(),
+            "{pieces} pieces do not fit in a u32 index"
+        );
+        anyhow::ensure!(
+            length.div_ceil(piece_length) == pieces,
+            "{pieces} piece hashes do not cover {length} bytes in pieces of {piece_length}"
+        );
+        Ok(())
+    }
+
+    /// The files described by the torrent, with a single-file torrent reported
+    /// as one file named after the torrent.
+    pub fn files(&self) -> Vec<File> {
         match self.info.keys {
-            Keys::SingleFile { length } => length,
-            Keys::MultiFile { ref files } => files.iter().map(|f| f.length).sum(),
+            Keys::SingleFile { length } => vec![File {
+                length,
+                path: vec![self.info.name.clone()],
+            }],
+            Keys::MultiFile { ref files } => files.clone(),
+        }
+    }
+
+    /// Where each of [`Torrent::files`] goes when downloading to `output`: the
+    /// file `output` itself for a single-file torrent, or the file's path
+    /// below the directory `output` for a multi-file one. Fails if a file's
+    /// path could lead outside `output`, see [`File::relative_path`].
+    pub fn output_paths(&self, output: &Path) -> Result<Vec<std::path::PathBuf>> {
+        match self.info.keys {
+            Keys::SingleFile { .. } => Ok(vec![output.to_path_buf()]),
+            Keys::MultiFile { ref files } => files
+                .iter()
+                .map(|file| Ok(output.join(file.relative_path()?)))
+                .collect(),
+        }
+    }
+
+    /// Yields `(piece_index, begin, length)` for every block request needed to
+    /// download the torrent, in order.
+    pub fn all_blocks(&self) -> impl Iterator<Item = (usize, u32, u32)> + '_ {
+        self.info
+            .pieces_with_offsets()
+            .flat_map(|(piece_i, _, piece_len, _)| {
+                (0..piece_len as u32)
+                    .step_by(BLOCK_MAX_SIZE as usize)
+                    .map(move |begin| {
+                        let len = (piece_len as u32 - begin).min(BLOCK_MAX_SIZE);
+                        (piece_i, begin, len)
+                    })
+            })
+    }
+
+    /// The download geometry, without contacting anyone.
+    pub fn plan(&self) -> Plan {
+        let last_piece = self.info.pieces_with_offsets().last();
+        Plan {
+            pieces: self.info.pieces.0.len(),
+            block_size: BLOCK_MAX_SIZE,
+            blocks: self.all_blocks().count(),
+            last_piece: last_piece.map_or(0, |(_, _, len, _)| len),
+            last_block: self.all_blocks().last().map_or(0, |(_, _, len)| len),
         }
     }
 
     pub async fn download_all(self) -> Result<Downloaded> {
-        crate::download::download_all(self).await
+        self.download_all_with(&DownloadConfig::default()).await
+    }
+
+    pub async fn download_all_with(self, config: &DownloadConfig) -> Result<Downloaded> {
+        crate::download::download_all(self, config).await
+    }
+
+    /// Downloads the torrent into the files at [`Torrent::output_paths`],
+    /// writing each piece as soon as it verifies.
+    pub async fn download_to_disk(&self, output: &Path, config: &DownloadConfig) -> Result<()> {
+        crate::download::download_to_disk(self, output, config).await
     }
+
+    /// Downloads the whole torrent from `peer` alone, e.g. for a LAN transfer.
+    /// Fails if the peer lacks any piece.
+    pub async fn download_all_single_peer(self, peer: SocketAddr) -> Result<Downloaded> {
+        crate::download::download_all_single_peer(self, peer, &DownloadConfig::default()).await
+    }
+}
+
+/// How a torrent breaks down into requests, see [`Torrent::plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub pieces: usize,
+    pub block_size: u32,
+    pub blocks: usize,
+    pub last_piece: usize,
+    pub last_block: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Pieces: {}", self.pieces)?;
+        writeln!(f, "Block Size: {}", self.block_size)?;
+        writeln!(f, "Blocks: {}", self.blocks)?;
+        writeln!(f, "Last Piece Size: {}", self.last_piece)?;
+        write!(f, "Last Block Size: {}", self.last_block)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Info {
     pub name: String,
     #[serde(rename = "piece length")]
@@ -62,20 +287,62 @@ pub struct Info {
     pub keys: Keys,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Info {
+    pub fn length(&self) -> usize {
+        match self.keys {
+            Keys::SingleFile { length } => length,
+            Keys::MultiFile { ref files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Yields `(index, offset, length, hash)` for every piece; only the last
+    /// piece can be shorter than `piece_length`.
+    pub fn pieces_with_offsets(&self) -> impl Iterator<Item = (usize, u64, usize, &[u8; 20])> {
+        let (length, piece_length) = (self.length() as u64, self.piece_length as u64);
+        self.pieces
+            .0
+            .iter()
+            .enumerate()
+            .map(move |(piece_i, hash)| {
+                let offset = piece_i as u64 * piece_length;
+                let len = length.saturating_sub(offset).min(piece_length);
+                (piece_i, offset, len as usize, hash)
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Keys {
     SingleFile { length: usize },
     MultiFile { files: Vec<File> },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct File {
     pub length: usize,
     pub path: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+impl File {
+    /// The file's path below the torrent's directory. Fails unless every
+    /// component is a plain name, so `..`, `.`, empty, absolute or
+    /// separator-containing components cannot lead outside that directory.
+    pub fn relative_path(&self) -> Result<std::path::PathBuf> {
+        anyhow::ensure!(!self.path.is_empty(), "file has an empty path");
+        for component in &self.path {
+            let mut parts = Path::new(component).components();
+            let plain = matches!(
+                (parts.next(), parts.next()),
+                (Some(std::path::Component::Normal(name)), None) if name == component.as_str()
+            );
+            anyhow::ensure!(plain, "unsafe path component {component:?} in file path");
+        }
+        Ok(self.path.iter().collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Hashes(pub Vec<[u8; 20]>);
 struct HashesVisitor;
 
@@ -90,7 +357,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: serde::de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             Err(E::invalid_length(v.len(), &self))
         } else {
             Ok(Hashes(
@@ -120,3 +387,318 @@ impl<'de> Deserialize<'de> for Hashes {
         deserializer.deserialize_bytes(HashesVisitor)
     }
 }
+
+#[tokio::test]
+async fn mapped_matches_read() {
+    let read = Torrent::read("sample.torrent").await.unwrap();
+    let mapped = Torrent::read_mapped("sample.torrent").unwrap();
+    assert_eq!(mapped, read);
+}
+
+#[test]
+fn files_are_normalized() {
+    let mut t = crate::mock::torrent(4, b"0123456789");
+    assert_eq!(
+        t.files(),
+        [File {
+            length: 10,
+            path: vec![String::from("mock.bin")],
+        }]
+    );
+
+    let files = vec![
+        File {
+            length: 6,
+            path: vec![String::from("a"), String::from("b.bin")],
+        },
+        File {
+            length: 4,
+            path: vec![String::from("c.bin")],
+        },
+    ];
+    t.info.keys = Keys::MultiFile {
+        files: files.clone(),
+    };
+    assert_eq!(t.files(), files);
+}
+
+#[test]
+fn announce_host_drops_path_and_query() {
+    let mut t = crate::mock::torrent(4, b"data");
+    t.announce = String::from("http://tracker.example.com/announce?passkey=secret");
+    assert_eq!(
+        t.announce_host().as_deref(),
+        Some("http://tracker.example.com:80")
+    );
+    t.announce = String::from("udp://tracker.opentrackr.org:1337/announce");
+    assert_eq!(
+        t.announce_host().as_deref(),
+        Some("udp://tracker.opentrackr.org:1337")
+    );
+    t.announce = String::from("not a url");
+    assert_eq!(t.announce_host(), None);
+}
+
+/// Walks the bencoded value at the start of `encoded` and returns where it
+/// runs out of input: the end of an unterminated integer, list or dictionary,
+/// or the start of a string longer than the bytes left. `None` if the value is
+/// complete, or malformed in some other way.
+fn truncated_at(encoded: &[u8]) -> Option<usize> {
+    /// Where the value starting at `pos` ends, or `Err` with the offset where
+    /// the input ran out.
+    fn value_end(encoded: &[u8], pos: usize) -> Result<Option<usize>, usize> {
+        match encoded.get(pos) {
+            None => Err(pos),
+            Some(b'i') => match encoded[pos..].iter().position(|&b| b == b'e') {
+                Some(e) => Ok(Some(pos + e + 1)),
+                None => Err(encoded.len()),
+            },
+            Some(b'l' | b'd') => {
+                let mut pos = pos + 1;
+                loop {
+                    match encoded.get(pos) {
+                        None => return Err(pos),
+                        Some(b'e') => return Ok(Some(pos + 1)),
+                        Some(_) => match value_end(encoded, pos)? {
+                            Some(end) => pos = end,
+                            None => return Ok(None),
+                        },
+                    }
+                }
+            }
+            Some(b'0'..=b'9') => {
+                let Some(colon) = encoded[pos..].iter().position(|&b| b == b':') else {
+                    return Err(encoded.len());
+                };
+                let Some(len) = std::str::from_utf8(&encoded[pos..pos + colon])
+                    .ok()
+                    .and_then(|len| len.parse::<usize>().ok())
+                else {
+                    return Ok(None);
+                };
+                let start = pos + colon + 1;
+                if len > encoded.len() - start {
+                    return Err(pos);
+                }
+                Ok(Some(start + len))
+            }
+            Some(_) => Ok(None),
+        }
+    }
+    value_end(encoded, 0).err()
+}
+
+#[test]
+fn truncated_torrent_is_reported() {
+    let dot_torrent = concat!(
+        "d",
+        "8:announce17:http://a/announce",
+        "4:infod6:lengthi4e4:name4:mock12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        "e",
+    );
+    assert!(Torrent::from_bytes(dot_torrent.as_bytes()).is_ok());
+
+    // Cut off in the middle of the piece hashes.
+    let pieces = dot_torrent.find("20:").unwrap();
+    let cut = &dot_torrent.as_bytes()[..pieces + 10];
+    let err = Torrent::from_bytes(cut).unwrap_err().to_string();
+    assert!(err.contains("truncated"), "{err}");
+    assert!(err.contains(&format!("byte {pieces} of")), "{err}");
+
+    // Every value is complete, but the dictionaries are never closed.
+    let cut = &dot_torrent.as_bytes()[..dot_torrent.len() - 2];
+    let err = Torrent::from_bytes(cut).unwrap_err().to_string();
+    assert!(err.contains(&format!("byte {} of", cut.len())), "{err}");
+}
+
+#[test]
+fn magnet_round_trips() {
+    let mut t = crate::mock::torrent(4, b"some data");
+    t.info.name = String::from("two words & more");
+    t.announce_list = Some(vec![
+        vec![t.announce.clone()],
+        vec![String::from("udp://tracker.example:6969/announce?key=a&b")],
+    ]);
+    let uri = t.to_magnet();
+    assert!(uri.starts_with("magnet:?xt=urn:btih:"), "{uri}");
+
+    let magnet = crate::magnet::Magnet::parse(&uri).expect("parse our own magnet");
+    assert_eq!(magnet.info_hash, t.info_hash());
+    assert_eq!(magnet.name.as_deref(), Some("two words & more"));
+    assert_eq!(magnet.trackers, t.trackers());
+}
+
+#[test]
+fn announce_list_tiers_are_flattened() {
+    let dot_torrent = concat!(
+        "d",
+        "8:announce17:http://a/announce",
+        "13:announce-listl",
+        "l17:http://b/announce17:http://a/announcee",
+        "l17:http://c/announcee",
+        "e",
+        "4:infod6:lengthi4e4:name4:mock12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        "e",
+    );
+    let t: Torrent = serde_bencode::from_str(dot_torrent).unwrap();
+    assert_eq!(
+        t.announce_list,
+        Some(vec![
+            vec![
+                String::from("http://b/announce"),
+                String::from("http://a/announce")
+            ],
+            vec![String::from("http://c/announce")],
+        ])
+    );
+    assert_eq!(
+        t.trackers(),
+        [
+            "http://b/announce",
+            "http://a/announce",
+            "http://c/announce"
+        ]
+    );
+
+    let t = crate::mock::torrent(4, b"data");
+    assert_eq!(t.trackers(), [t.announce.as_str()]);
+}
+
+#[test]
+fn oversized_piece_is_rejected() {
+    let mut t = crate::mock::torrent(4, b"01234567");
+    assert!(t.validate().is_ok());
+
+    // The second piece would start at 2^32, past any u32 offset.
+    t.info.piece_length = u32::MAX as usize + 1;
+    t.info.keys = Keys::SingleFile {
+        length: t.info.piece_length + 1,
+    };
+    let err = t.validate().unwrap_err();
+    assert!(err.to_string().contains("does not fit"), "{err}");
+
+    t.info.keys = Keys::MultiFile {
+        files: vec![
+            File {
+                length: usize::MAX,
+                path: vec![String::from("a")],
+            },
+            File {
+                length: 1,
+                path: vec![String::from("b")],
+            },
+        ],
+    };
+    assert!(t.validate().is_err());
+}
+
+#[test]
+fn escaping_file_paths_are_rejected() {
+    let mut t = crate::mock::torrent(4, b"01234567");
+    for path in [
+        vec!["..", "escaped"],
+        vec!["a", ".", "b"],
+        vec!["/etc", "cron.d", "x"],
+        vec!["a/../../b"],
+        vec![""],
+        vec![],
+    ] {
+        let file = File {
+            length: 8,
+            path: path.iter().map(|c| c.to_string()).collect(),
+        };
+        assert!(file.relative_path().is_err(), "{path:?}");
+        t.info.keys = Keys::MultiFile { files: vec![file] };
+        let err = t.validate().unwrap_err();
+        assert!(err.to_string().contains("path"), "{path:?}: {err}");
+    }
+
+    let file = File {
+        length: 8,
+        path: vec![String::from("sub"), String::from("b.bin")],
+    };
+    assert_eq!(
+        file.relative_path().unwrap(),
+        Path::new("sub").join("b.bin")
+    );
+
+    t.info.keys = Keys::MultiFile {
+        files: vec![
+            file,
+            File {
+                length: 8,
+                path: vec![String::from(".."), String::from("escaped")],
+            },
+        ],
+    };
+    assert!(t.output_paths(Path::new("out")).is_err());
+}
+
+#[test]
+fn piece_offsets_with_short_last_piece() {
+    let t = crate::mock::torrent(4, b"0123456789");
+    let pieces: Vec<_> = t
+        .info
+        .pieces_with_offsets()
+        .map(|(i, offset, len, &hash)| (i, offset, len, hash))
+        .collect();
+    let hash = |piece: &[u8]| -> [u8; 20] { Sha1::digest(piece).into() };
+    assert_eq!(
+        pieces,
+        [
+            (0, 0, 4, hash(b"0123")),
+            (1, 4, 4, hash(b"4567")),
+            (2, 8, 2, hash(b"89")),
+        ]
+    );
+
+    assert_eq!(t.pieces_overlapping(3, 5), 0..2);
+    assert_eq!(t.pieces_overlapping(4, 8), 1..2);
+    assert_eq!(t.pieces_overlapping(9, 100), 2..3);
+    assert_eq!(t.pieces_overlapping(6, 6), 0..0);
+}
+
+#[test]
+fn locate_offsets_around_file_boundary() {
+    let block = BLOCK_MAX_SIZE as usize;
+    let mut t = crate::mock::torrent(2 * block, &vec![0u8; 5 * block]);
+    // The second file starts one byte short of the second piece.
+    t.info.keys = Keys::MultiFile {
+        files: vec![
+            File {
+                length: 2 * block - 1,
+                path: vec![String::from("a")],
+            },
+            File {
+                length: 3 * block + 1,
+                path: vec![String::from("b")],
+            },
+        ],
+    };
+
+    assert_eq!(t.locate(0, 2 * block as u64 - 2), Some((0, BLOCK_MAX_SIZE)));
+    assert_eq!(t.locate(1, 0), Some((0, BLOCK_MAX_SIZE)));
+    assert_eq!(t.locate(1, 1), Some((1, 0)), "first byte of a piece");
+    assert_eq!(t.locate(1, block as u64 + 1), Some((1, BLOCK_MAX_SIZE)));
+    assert_eq!(t.locate(1, 3 * block as u64), Some((2, 0)), "last byte");
+    assert_eq!(t.locate(1, 3 * block as u64 + 1), None);
+    assert_eq!(t.locate(2, 0), None);
+}
+
+#[test]
+fn plan_counts_blocks() {
+    let block = BLOCK_MAX_SIZE as usize;
+    let data = vec![7u8; 5 * block + 100];
+    let t = crate::mock::torrent(2 * block, &data);
+    let plan = t.plan();
+
+    assert_eq!(plan.blocks, data.len().div_ceil(block));
+    assert_eq!(plan.pieces, 3);
+    assert_eq!(plan.last_piece, block + 100);
+    assert_eq!(plan.last_block, 100);
+    let printed = plan.to_string();
+    assert!(
+        printed.contains(&format!("Blocks: {}\n", data.len().div_ceil(block))),
+        "{printed}"
+    );
+}
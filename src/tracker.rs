@@ -1,9 +1,15 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize, de::Visitor};
 
-use crate::torrent::Torrent;
+use crate::{magnet::Magnet, peer::PeerId, torrent::Torrent};
+
+mod udp;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
@@ -13,85 +19,674 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<AnnounceEvent>,
+}
+
+/// Lifecycle events reported to the tracker alongside an announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
+    #[serde(deserialize_with = "deserialize_interval")]
     pub interval: usize,
+    /// Announcing more often than this may get us banned by the tracker.
+    #[serde(
+        rename = "min interval",
+        default,
+        deserialize_with = "deserialize_min_interval"
+    )]
+    pub min_interval: Option<usize>,
+    /// IPv4 peers; an IPv6-only tracker may leave them out.
+    #[serde(default)]
     pub peers: Peers,
+    /// IPv6 peers (BEP 7), which the compact `peers` list has no room for.
+    #[serde(default, deserialize_with = "deserialize_peers6")]
+    pub peers6: Peers,
 }
 
 impl TrackerResponse {
-    pub(crate) async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+    /// Every peer the tracker returned, IPv4 first.
+    pub fn all_peers(&self) -> Vec<SocketAddr> {
+        self.peers.0.iter().chain(&self.peers6.0).copied().collect()
+    }
+}
+
+/// Announces to trackers and keeps a per-tracker history of the outcomes.
+#[derive(Debug)]
+pub struct TrackerClient {
+    client: reqwest::Client,
+    stats: BTreeMap<String, TrackerStats>,
+    /// How many times an announce is retried when the tracker cannot be
+    /// resolved or connected to, or a UDP tracker does not answer.
+    connect_retries: u32,
+    /// Whether announces must go through a SOCKS5 proxy. UDP trackers are
+    /// refused then, as their packets would go around it.
+    proxied: bool,
+    /// Whether `http` tracker URLs are announced to over `https`.
+    https_upgrade: bool,
+    /// Tracker tiers per info hash, kept so a tracker that answered stays
+    /// first in its tier for the next announce (BEP 12).
+    tiers: BTreeMap<[u8; 20], TrackerTiers>,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::default(),
+            stats: BTreeMap::new(),
+            connect_retries: 3,
+            proxied: false,
+            https_upgrade: true,
+            tiers: BTreeMap::new(),
+        }
+    }
+}
+
+impl TrackerClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A client whose announces go through the SOCKS5 proxy.
+    pub fn with_proxy(proxy: &crate::socks::Socks5Config) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(proxy.url()).context("configure tracker proxy")?)
+            .build()
+            .context("build tracker client")?;
+        Ok(Self {
+            client,
+            proxied: true,
+            ..Self::default()
+        })
+    }
+
+    pub fn with_connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Announce to `http` trackers as given instead of over `https`.
+    pub fn with_https_upgrade(mut self, upgrade: bool) -> Self {
+        self.https_upgrade = upgrade;
+        self
+    }
+
+    /// Announces `t` to its trackers tier by tier until one answers, see
+    /// [`TrackerClient::announce_tiers`]. The tier order this leaves is kept
+    /// for the next query of the same torrent.
+    pub async fn query(&mut self, t: &Torrent) -> anyhow::Result<TrackerResponse> {
+        let info_hash = t.info_hash();
+        let mut tiers = self
+            .tiers
+            .remove(&info_hash)
+            .unwrap_or_else(|| t.tracker_tiers());
+        let response = self
+            .announce_tiers(&mut tiers, info_hash, TrackerRequest::new(t.length()))
+            .await;
+        self.tiers.insert(info_hash, tiers);
+        response
+    }
+
+    /// Announces a torrent known only from `magnet`, before its metadata is
+    /// fetched. Without an `xl` length we cannot tell how much is left, so
+    /// `left` is reported as 0 until the metadata says otherwise.
+    pub async fn query_magnet(&mut self, magnet: &Magnet) -> anyhow::Result<TrackerResponse> {
+        let left = magnet.length.map_or(0, |length| length as usize);
+        let mut tiers = TrackerTiers::new(vec![magnet.trackers.clone()]);
+        self.announce_tiers(&mut tiers, magnet.info_hash, TrackerRequest::new(left))
+            .await
+    }
+
+    /// Announces `event` for `t` as a peer listening on `port` that still needs
+    /// `left` bytes.
+    pub async fn query_event(
+        &mut self,
+        t: &Torrent,
+        port: u16,
+        left: usize,
+        event: Option<AnnounceEvent>,
+    ) -> anyhow::Result<TrackerResponse> {
         let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: t.length(),
-            compact: 1,
+            port,
+            event,
+            ..TrackerRequest::new(left)
         };
+        self.announce_with(self.announce_url(&t.announce)?, t.info_hash(), request)
+            .await
+    }
 
+    pub async fn announce(
+        &mut self,
+        tracker_url: reqwest::Url,
+        info_hash: [u8; 20],
+        left: usize,
+    ) -> anyhow::Result<TrackerResponse> {
+        self.announce_with(tracker_url, info_hash, TrackerRequest::new(left))
+            .await
+    }
+
+    pub async fn announce_with(
+        &mut self,
+        tracker_url: reqwest::Url,
+        info_hash: [u8; 20],
+        request: TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let tracker = tracker_url.to_string();
+        let response = if tracker_url.scheme() == "udp" && self.proxied {
+            Err(anyhow::anyhow!(
+                "UDP tracker skipped: it cannot be reached through the SOCKS5 proxy"
+            ))
+        } else if tracker_url.scheme() == "udp" {
+            udp::announce(&tracker_url, info_hash, request, self.connect_retries).await
+        } else {
+            announce(
+                &self.client,
+                tracker_url,
+                info_hash,
+                request,
+                self.connect_retries,
+            )
+            .await
+        };
+        self.stats.entry(tracker).or_default().record(&response);
+        response
+    }
+
+    /// Announces to the trackers of `tiers` in order until one answers, and
+    /// moves that tracker to the front of its tier (BEP 12).
+    pub async fn announce_tiers(
+        &mut self,
+        tiers: &mut TrackerTiers,
+        info_hash: [u8; 20],
+        request: TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let mut last_error = None;
+        for tier in &mut tiers.tiers {
+            for tracker_i in 0..tier.len() {
+                let response = match self.announce_url(&tier[tracker_i]) {
+                    Ok(url) => self.announce_with(url, info_hash, request.clone()).await,
+                    Err(e) => Err(e),
+                };
+                match response {
+                    Ok(response) => {
+                        let tracker = tier.remove(tracker_i);
+                        tier.insert(0, tracker);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("announce to {} failed: {e:#}", tier[tracker_i]);
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("no trackers to announce to"))
+            .context("every tracker failed"))
+    }
+
+    fn announce_url(&self, announce: &str) -> anyhow::Result<reqwest::Url> {
         let mut tracker_url =
-            reqwest::Url::parse(&t.announce).context("parse tracker announce URL")?;
-        if tracker_url.scheme() == "http" {
+            reqwest::Url::parse(announce).context("parse tracker announce URL")?;
+        if self.https_upgrade && tracker_url.scheme() == "http" {
             tracker_url
                 .set_scheme("https")
                 .expect("Failed to set HTTPS scheme");
         }
-        let url_params =
-            serde_urlencoded::to_string(request).context("serialize tracker request")?;
+        Ok(tracker_url)
+    }
 
-        let url_params = format!("info_hash={}&{}", &url_encode(&info_hash), url_params);
-        tracker_url.set_query(Some(&url_params));
+    /// Announce history for every tracker this client has contacted.
+    pub fn tracker_stats(&self) -> &BTreeMap<String, TrackerStats> {
+        &self.stats
+    }
+}
 
-        let client = reqwest::Client::new();
+/// Tracker URLs grouped into the tiers of an announce list; earlier tiers
+/// are preferred, see [`TrackerClient::announce_tiers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
 
-        let response = client
-            .get(tracker_url)
-            .send()
-            .await
-            .context("send tracker request")?;
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        Self { tiers }
+    }
 
-        let response = response.bytes().await.context("read tracker response")?;
-        let response: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("deserialize tracker response")?;
-        Ok(response)
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackerStats {
+    /// Most recent announces, oldest first.
+    pub history: VecDeque<AnnounceRecord>,
+}
+
+impl TrackerStats {
+    const HISTORY_LEN: usize = 16;
+
+    fn record(&mut self, response: &anyhow::Result<TrackerResponse>) {
+        if self.history.len() == Self::HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(AnnounceRecord {
+            at: SystemTime::now(),
+            interval: response.as_ref().ok().map(|r| r.interval),
+            peers: response
+                .as_ref()
+                .map(|r| r.peers.0.len() + r.peers6.0.len())
+                .map_err(|e| format!("{e:#}")),
+        });
+    }
+
+    pub fn last(&self) -> Option<&AnnounceRecord> {
+        self.history.back()
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+pub struct AnnounceRecord {
+    pub at: SystemTime,
+    pub interval: Option<usize>,
+    /// Number of peers returned, or the error the announce failed with.
+    pub peers: Result<usize, String>,
+}
+
+/// When to announce next: after the tracker's `interval`, or sooner when
+/// asked to, but never sooner than its `min interval`.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    last: tokio::time::Instant,
+    interval: Duration,
+    min_interval: Duration,
+    early: bool,
+}
+
+impl AnnounceSchedule {
+    /// Interval assumed when an announce fails and the tracker gave none.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// A schedule starting with an announce made just now.
+    pub fn new(response: Option<&TrackerResponse>) -> Self {
+        let mut schedule = Self {
+            last: tokio::time::Instant::now(),
+            interval: Self::RETRY_INTERVAL,
+            min_interval: Duration::ZERO,
+            early: false,
+        };
+        schedule.announced(response);
+        schedule
+    }
+
+    /// Records an announce made just now, with the response if it succeeded.
+    pub fn announced(&mut self, response: Option<&TrackerResponse>) {
+        self.last = tokio::time::Instant::now();
+        self.early = false;
+        if let Some(response) = response {
+            self.interval = Duration::from_secs(response.interval as u64);
+            self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0) as u64);
+        }
+    }
+
+    /// Asks for an announce before the interval is up, e.g. because the
+    /// download stalled. It still waits out `min interval`.
+    pub fn announce_early(&mut self) {
+        self.early = true;
+    }
+
+    pub fn next_at(&self) -> tokio::time::Instant {
+        let wait = if self.early {
+            self.min_interval.min(self.interval)
+        } else {
+            self.interval
+        };
+        self.last + wait
+    }
+
+    /// Waits until the next announce is due.
+    pub async fn wait(&self) {
+        tokio::time::sleep_until(self.next_at()).await
+    }
+}
+
+impl TrackerRequest {
+    /// Our usual announce for a peer that still needs `left` bytes.
+    pub fn new(left: usize) -> Self {
+        Self {
+            peer_id: PeerId::session().to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+            event: None,
+        }
+    }
+}
+
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+async fn announce(
+    client: &reqwest::Client,
+    mut tracker_url: reqwest::Url,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    connect_retries: u32,
+) -> anyhow::Result<TrackerResponse> {
+    let url_params = serde_urlencoded::to_string(request).context("serialize tracker request")?;
+
+    let url_params = format!("info_hash={}&{}", &url_encode(&info_hash), url_params);
+    tracker_url.set_query(Some(&url_params));
+
+    // Only failures to reach the tracker at all (DNS, refused connections)
+    // are retried; an HTTP error status is the tracker's answer.
+    let mut attempt = 0;
+    let response = loop {
+        let sent = client.get(tracker_url.clone()).send().await;
+        match sent {
+            Err(e) if e.is_connect() && attempt < connect_retries => {
+                attempt += 1;
+                eprintln!("tracker unreachable, retrying ({attempt}/{connect_retries}): {e}");
+                tokio::time::sleep(CONNECT_RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+            }
+            sent => break sent.context("send tracker request")?,
+        }
+    };
+
+    // reqwest has already undone any gzip or deflate Content-Encoding.
+    let response = response.bytes().await.context("read tracker response")?;
+    let response: TrackerResponse =
+        serde_bencode::from_bytes(&response).context("deserialize tracker response")?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn tracker_stats_history() {
+    let body = b"d8:intervali900e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e";
+    let response = crate::mock::http_response(&[], body);
+    let (addr, tracker) = crate::mock::http_server(vec![response.clone(), response]).await;
+
+    let url = reqwest::Url::parse(&format!("http://{addr}/announce")).unwrap();
+    let mut client = TrackerClient::new();
+    for _ in 0..2 {
+        client
+            .announce(url.clone(), [0u8; 20], 100)
+            .await
+            .expect("announce to mock tracker");
+    }
+    tracker.await.unwrap();
+
+    let stats = &client.tracker_stats()[url.as_str()];
+    assert_eq!(stats.history.len(), 2);
+    for record in &stats.history {
+        assert_eq!(record.peers, Ok(2));
+        assert_eq!(record.interval, Some(900));
+    }
+}
+
+#[tokio::test]
+async fn udp_tracker_is_refused_behind_proxy() {
+    let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url =
+        reqwest::Url::parse(&format!("udp://{}/announce", tracker.local_addr().unwrap())).unwrap();
+    let proxy = crate::socks::Socks5Config {
+        addr: "127.0.0.1:1080".parse().unwrap(),
+        credentials: None,
+    };
+    let mut client = TrackerClient::with_proxy(&proxy).unwrap();
+    let err = client
+        .announce(url, [0u8; 20], 100)
+        .await
+        .expect_err("UDP announce must not bypass the proxy");
+    assert!(err.to_string().contains("SOCKS5"), "{err}");
+
+    let mut buf = [0u8; 64];
+    let sent = tokio::time::timeout(Duration::from_millis(100), tracker.recv(&mut buf)).await;
+    assert!(sent.is_err(), "a packet reached the tracker");
+}
+
+#[tokio::test]
+async fn retries_unreachable_tracker() {
+    // Nothing listens on the port until after the first attempt fails.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap();
+    let tracker = tokio::spawn(async move {
+        tokio::time::sleep(CONNECT_RETRY_BACKOFF / 2).await;
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 1024];
+        let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut request).await;
+        let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &crate::mock::http_response(&[], body))
+            .await
+            .unwrap();
+    });
+
+    let url = reqwest::Url::parse(&format!("http://{addr}/announce")).unwrap();
+    let mut client = TrackerClient::new().with_connect_retries(2);
+    let response = client
+        .announce(url, [0u8; 20], 100)
+        .await
+        .expect("announce after retry");
+    assert_eq!(response.interval, 60);
+    tracker.await.unwrap();
+}
+
+#[tokio::test]
+async fn gzip_tracker_response() {
+    // gzip of `d8:intervali1800e5:peers12:<127.0.0.1:6881><10.0.0.2:6882>e`
+    let body = hex::decode(
+        "1f8b08000000000002034bb1b0cacc2b492d2a4bccc934b430304835b52a484d2d2a3634b2aa676060947ac8c5c0c024f52815004368c2c628000000",
+    )
+    .unwrap();
+    let (addr, tracker) = crate::mock::http_server(vec![crate::mock::http_response(
+        &[("Content-Encoding", "gzip")],
+        &body,
+    )])
+    .await;
+
+    let url = reqwest::Url::parse(&format!("http://{addr}/announce")).unwrap();
+    let response = TrackerClient::new()
+        .announce(url, [0u8; 20], 100)
+        .await
+        .expect("announce to mock tracker");
+    assert_eq!(response.interval, 1800);
+    assert_eq!(
+        response.peers.0,
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap(),
+        ]
+    );
+    tracker.await.unwrap();
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
 struct PeersVisitor;
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("6 bytes, the first 4 bytes are peer's IP address and the last 2 are a peer's port number")
+        formatter.write_str("6 bytes per peer, the first 4 bytes are peer's IP address and the last 2 are a peer's port number, or a list of peer dictionaries")
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
-            Err(E::custom("Invalid peer list length"))
-        } else {
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .map(|chunk| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                            u16::from_be_bytes([chunk[4], chunk[5]]),
-                        )
-                    })
-                    .collect(),
-            ))
+        Ok(compact_peers(v, 4))
+    }
+
+    /// Some trackers hand the compact blob over as a string.
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_bytes(v.as_bytes())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_bytes(&v)
+    }
+
+    /// The non-compact form some trackers send regardless of `compact=1`.
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        let mut skipped = 0;
+        while let Some(peer) = seq.next_element::<DictPeer>()? {
+            let addr = peer
+                .ip
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .zip(peer.port.and_then(|port| u16::try_from(port).ok()))
+                .map(|(ip, port)| SocketAddr::new(ip, port))
+                .filter(is_usable_peer);
+            match addr {
+                Some(addr) => peers.push(addr),
+                None => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            eprintln!("skipped {skipped} unusable peer entries in tracker response");
         }
+        Ok(Peers(peers))
     }
 }
 
+/// An entry of the non-compact peer list. Entries missing a field, or with
+/// a host name instead of an address, are skipped rather than failing the
+/// announce.
+#[derive(Deserialize)]
+struct DictPeer {
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    port: Option<i64>,
+}
+
+#[test]
+fn peers_from_str_match_bytes() {
+    use serde::de::value::{BytesDeserializer, Error, StrDeserializer};
+
+    // 65.66.67.68:6753 and 10.0.0.3:26978, all ASCII so it is also a valid str.
+    let blob = "ABCD\x1aa\x0a\x00\x00\x03ib";
+    let from_bytes = Peers::deserialize(BytesDeserializer::<Error>::new(blob.as_bytes())).unwrap();
+    let from_str = Peers::deserialize(StrDeserializer::<Error>::new(blob)).unwrap();
+    let from_buf = PeersVisitor
+        .visit_byte_buf::<Error>(blob.as_bytes().to_vec())
+        .unwrap();
+
+    assert_eq!(
+        from_bytes.0,
+        vec![
+            "65.66.67.68:6753".parse::<SocketAddr>().unwrap(),
+            "10.0.0.3:26978".parse().unwrap(),
+        ]
+    );
+    assert_eq!(from_str.0, from_bytes.0);
+    assert_eq!(from_buf.0, from_bytes.0);
+}
+
+#[test]
+fn dict_peers_match_compact() {
+    let compact: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e",
+    )
+    .unwrap();
+    let dict: TrackerResponse = serde_bencode::from_bytes(
+        concat!(
+            "d8:intervali60e5:peersl",
+            "d2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee",
+            "d2:ip16:tracker.example.4:porti1ee",
+            "d2:ip8:10.0.0.24:porti6882ee",
+            "d2:ip8:10.0.0.34:porti70000ee",
+            "ee",
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    assert_eq!(dict.peers.0, compact.peers.0);
+    assert_eq!(compact.peers.0.len(), 2);
+}
+
+/// Splits a compact peer list into entries of an `ip_len`-byte address and a
+/// port, dropping unusable addresses.
+fn compact_peers(v: &[u8], ip_len: usize) -> Peers {
+    let chunks = v.chunks_exact(ip_len + 2);
+    let trailing = chunks.remainder().len();
+    let total = chunks.len();
+    let peers: Vec<_> = chunks
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(ip_len);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(ip) => IpAddr::from(Ipv6Addr::from(ip)),
+                Err(_) => IpAddr::from(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .filter(is_usable_peer)
+        .collect();
+
+    let skipped = total - peers.len();
+    if skipped > 0 || trailing > 0 {
+        eprintln!(
+            "skipped {skipped} unusable peer addresses and {trailing} trailing bytes in tracker response"
+        );
+    }
+    Peers(peers)
+}
+
+/// Filters out entries that cannot be a reachable peer, such as the zeroed
+/// bytes some trackers emit for I2P or onion peers.
+fn is_usable_peer(addr: &SocketAddr) -> bool {
+    let usable_ip = match addr.ip() {
+        IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_broadcast() && !ip.is_multicast(),
+        IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+    };
+    addr.port() != 0 && usable_ip
+}
+
+#[test]
+fn peers_skip_unusable_entries() {
+    let blob = [
+        127, 0, 0, 1, 0x1a, 0xe1, // 127.0.0.1:6881
+        0, 0, 0, 0, 0x1a, 0xe1, // unspecified address
+        255, 255, 255, 255, 0, 80, // broadcast
+        10, 0, 0, 2, 0, 0, // port zero
+        10, 0, 0, 3, 0x1a, 0xe2, // 10.0.0.3:6882
+        0x6f, 0x6e, 0x69, // trailing garbage
+    ];
+    let mut bencoded = format!("{}:", blob.len()).into_bytes();
+    bencoded.extend_from_slice(&blob);
+
+    let peers: Peers = serde_bencode::from_bytes(&bencoded).expect("skip bad entries");
+    assert_eq!(
+        peers.0,
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.3:6882".parse().unwrap(),
+        ]
+    );
+}
+
 impl<'de> Deserialize<'de> for Peers {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -101,6 +696,133 @@ impl<'de> Deserialize<'de> for Peers {
     }
 }
 
+/// The compact `peers6` list: 16 bytes of IPv6 address and 2 of port each.
+struct Peers6Visitor;
+
+impl<'de> Visitor<'de> for Peers6Visitor {
+    type Value = Peers;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("18 bytes per peer, the first 16 bytes are peer's IPv6 address and the last 2 are a peer's port number")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(compact_peers(v, 16))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_bytes(v.as_bytes())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_bytes(&v)
+    }
+}
+
+fn deserialize_peers6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserializer.deserialize_bytes(Peers6Visitor)
+}
+
+/// Interval assumed when a tracker sends one we cannot make sense of.
+const FALLBACK_INTERVAL: usize = 1800;
+
+/// Reads an announce interval in seconds. Some trackers send it as a string,
+/// possibly with a fraction; anything else that is not a number is replaced
+/// by `None` rather than failing the announce.
+fn lenient_interval<'de, D>(deserializer: D, field: &str) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_bencode::value::Value::deserialize(deserializer)?;
+    let interval = match &value {
+        serde_bencode::value::Value::Int(seconds) => usize::try_from(*seconds).ok(),
+        serde_bencode::value::Value::Bytes(seconds) => std::str::from_utf8(seconds)
+            .ok()
+            .and_then(|seconds| seconds.trim().parse::<f64>().ok())
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(|seconds| seconds.round() as usize),
+        _ => None,
+    };
+    if interval.is_none() {
+        eprintln!("ignoring tracker {field} that is not a number of seconds: {value:?}");
+    }
+    Ok(interval)
+}
+
+fn deserialize_interval<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(lenient_interval(deserializer, "interval")?.unwrap_or(FALLBACK_INTERVAL))
+}
+
+fn deserialize_min_interval<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    lenient_interval(deserializer, "min interval")
+}
+
+#[test]
+fn interval_given_as_string() {
+    let response: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:interval4:180012:min interval4:60.55:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+    )
+    .expect("string intervals parse");
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.min_interval, Some(61));
+
+    let response: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:intervalli60ee12:min interval5:later5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+    )
+    .expect("unusable intervals fall back");
+    assert_eq!(response.interval, FALLBACK_INTERVAL);
+    assert_eq!(response.min_interval, None);
+}
+
+#[test]
+fn compact_peers_of_both_families() {
+    let response: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e",
+    )
+    .unwrap();
+    assert_eq!(
+        response.peers.0,
+        ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(
+        response.peers6.0,
+        ["[2001:db8::1]:6882".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(response.all_peers().len(), 2);
+
+    let v4_only: TrackerResponse =
+        serde_bencode::from_bytes(b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").unwrap();
+    assert!(v4_only.peers6.0.is_empty());
+
+    let v6_only: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:intervali60e6:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e",
+    )
+    .unwrap();
+    assert!(v6_only.peers.0.is_empty());
+    assert_eq!(
+        v6_only.all_peers(),
+        ["[2001:db8::1]:6882".parse::<SocketAddr>().unwrap()]
+    );
+}
+
 pub fn url_encode(bytes: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(40);
     for &byte in bytes {
@@ -109,3 +831,106 @@ pub fn url_encode(bytes: &[u8; 20]) -> String {
     }
     encoded
 }
+
+#[tokio::test(start_paused = true)]
+async fn early_announce_waits_for_min_interval() {
+    let response: TrackerResponse = serde_bencode::from_bytes(
+        b"d8:intervali1800e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+    )
+    .unwrap();
+    assert_eq!(response.min_interval, Some(60));
+    let start = tokio::time::Instant::now();
+    let mut schedule = AnnounceSchedule::new(Some(&response));
+
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    // The download stalled.
+    schedule.announce_early();
+    schedule.wait().await;
+    assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+    schedule.announced(Some(&response));
+    schedule.wait().await;
+    assert_eq!(start.elapsed(), Duration::from_secs(60 + 1800));
+}
+
+#[tokio::test]
+async fn answering_tracker_moves_to_front_of_tier() {
+    let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+    let (broken, broken_server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], b"oops")]).await;
+    let (working, working_server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], body); 2]).await;
+    let (broken, working) = (
+        format!("http://{broken}/announce"),
+        format!("http://{working}/announce"),
+    );
+    let mut tiers = TrackerTiers::new(vec![vec![broken.clone(), working.clone()]]);
+
+    let mut client = TrackerClient::new().with_https_upgrade(false);
+    let response = client
+        .announce_tiers(&mut tiers, [0u8; 20], TrackerRequest::new(100))
+        .await
+        .expect("second tracker answers");
+    assert_eq!(response.interval, 60);
+    assert_eq!(tiers.tiers(), [vec![working.clone(), broken.clone()]]);
+
+    client
+        .announce_tiers(&mut tiers, [0u8; 20], TrackerRequest::new(100))
+        .await
+        .expect("promoted tracker answers");
+    assert_eq!(broken_server.await.unwrap().len(), 1, "tried once only");
+    assert_eq!(working_server.await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn query_keeps_tier_order_between_announces() {
+    let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+    let (broken, broken_server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], b"oops")]).await;
+    let (working, working_server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], body); 2]).await;
+    let mut t = crate::mock::torrent(4, b"data");
+    t.announce_list = Some(vec![vec![
+        format!("http://{broken}/announce"),
+        format!("http://{working}/announce"),
+    ]]);
+
+    let mut client = TrackerClient::new().with_https_upgrade(false);
+    for _ in 0..2 {
+        client.query(&t).await.expect("second tracker answers");
+    }
+    assert_eq!(broken_server.await.unwrap().len(), 1, "tried once only");
+    assert_eq!(working_server.await.unwrap().len(), 2);
+
+    let mut client = TrackerClient::new().with_connect_retries(0);
+    let err = client.query(&t).await.expect_err("no TLS on the mock");
+    assert!(
+        client
+            .tracker_stats()
+            .keys()
+            .all(|tracker| tracker.starts_with("https://")),
+        "{err:#}"
+    );
+}
+
+#[tokio::test]
+async fn magnet_announces_before_metadata() {
+    let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+    let (tracker, server) =
+        crate::mock::http_server(vec![crate::mock::http_response(&[], body)]).await;
+    let magnet = Magnet::parse(&format!(
+        "magnet:?xt=urn:btih:{}&tr=http%3A%2F%2F{tracker}%2Fannounce",
+        "ab".repeat(20)
+    ))
+    .unwrap();
+    assert_eq!(magnet.length, None);
+
+    let response = TrackerClient::new()
+        .with_https_upgrade(false)
+        .query_magnet(&magnet)
+        .await
+        .expect("announce without a length");
+    assert_eq!(response.peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+    let requests = server.await.unwrap();
+    assert!(requests[0].contains("&left=0&"), "{}", requests[0]);
+}
@@ -0,0 +1,232 @@
+//! The UDP tracker protocol (BEP 15): a connect exchange for a connection
+//! id, then an announce carrying it.
+
+use std::{
+    hash::{BuildHasher, Hasher},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use tokio::net::UdpSocket;
+
+use super::{AnnounceEvent, Peers, TrackerRequest, TrackerResponse};
+
+/// Magic `protocol_id` every connect request starts with.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const ERROR: u32 = 3;
+
+/// Timeout before the first retransmit; it doubles on every retransmit after.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long to wait for an answer after `retransmits` earlier tries went
+/// unanswered: `15 * 2^n` seconds, as BEP 15 prescribes.
+fn retransmit_timeout(retransmits: u32) -> Duration {
+    RETRANSMIT_TIMEOUT * 2u32.pow(retransmits.min(8))
+}
+
+/// Announces to the UDP tracker at `tracker_url`, retransmitting each request
+/// up to `retransmits` times when the tracker does not answer.
+pub(super) async fn announce(
+    tracker_url: &reqwest::Url,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    retransmits: u32,
+) -> Result<TrackerResponse> {
+    let host = tracker_url
+        .host_str()
+        .context("UDP tracker URL has no host")?;
+    let port = tracker_url.port().context("UDP tracker URL has no port")?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("bind UDP socket")?;
+    socket
+        .connect((host, port))
+        .await
+        .with_context(|| format!("connect to UDP tracker {host}:{port}"))?;
+
+    let reply = exchange(&socket, PROTOCOL_ID, CONNECT, &[], retransmits)
+        .await
+        .context("UDP tracker connect")?;
+    ensure!(
+        reply.len() >= 8,
+        "connect response is {} bytes",
+        reply.len() + 8
+    );
+    let connection_id = u64::from_be_bytes(reply[..8].try_into().unwrap());
+
+    ensure!(
+        request.peer_id.len() == 20,
+        "peer id must be 20 bytes, got {}",
+        request.peer_id.len()
+    );
+    let event: u32 = match request.event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    };
+    let mut announce = Vec::with_capacity(82);
+    announce.extend_from_slice(&info_hash);
+    announce.extend_from_slice(request.peer_id.as_bytes());
+    announce.extend_from_slice(&(request.downloaded as u64).to_be_bytes());
+    announce.extend_from_slice(&(request.left as u64).to_be_bytes());
+    announce.extend_from_slice(&(request.uploaded as u64).to_be_bytes());
+    announce.extend_from_slice(&event.to_be_bytes());
+    // Let the tracker use the address the packet came from.
+    announce.extend_from_slice(&0u32.to_be_bytes());
+    // The key tells the tracker it is still us if our address changes.
+    announce.extend_from_slice(&transaction_id().to_be_bytes());
+    // Ask for the tracker's default number of peers.
+    announce.extend_from_slice(&(-1i32).to_be_bytes());
+    announce.extend_from_slice(&request.port.to_be_bytes());
+    let reply = exchange(&socket, connection_id, ANNOUNCE, &announce, retransmits)
+        .await
+        .context("UDP tracker announce")?;
+
+    ensure!(
+        reply.len() >= 12,
+        "announce response is {} bytes",
+        reply.len() + 8
+    );
+    let interval = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let peers = reply[12..]
+        .chunks_exact(6)
+        .map(|peer| {
+            SocketAddr::from(SocketAddrV4::new(
+                Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                u16::from_be_bytes([peer[4], peer[5]]),
+            ))
+        })
+        .collect();
+    Ok(TrackerResponse {
+        interval: interval as usize,
+        min_interval: None,
+        peers: Peers(peers),
+        peers6: Peers::default(),
+    })
+}
+
+/// Sends a request for `action` under a fresh transaction id, retransmitting
+/// it until the tracker answers. Every request starts with `connection_id`
+/// (or the protocol id, to connect), the action and the transaction id before
+/// `body`. Returns what follows the action and transaction id of the first
+/// reply that echoes ours.
+async fn exchange(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: u32,
+    body: &[u8],
+    retransmits: u32,
+) -> Result<Vec<u8>> {
+    let transaction_id = transaction_id();
+    let mut packet = Vec::with_capacity(16 + body.len());
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&action.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(body);
+
+    let mut buf = vec![0u8; 2048];
+    for attempt in 0..=retransmits {
+        socket.send(&packet).await.context("send to UDP tracker")?;
+        let deadline = tokio::time::Instant::now() + retransmit_timeout(attempt);
+        loop {
+            let received = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(received) => received.context("receive from UDP tracker")?,
+                Err(_) => break,
+            };
+            let reply = &buf[..received];
+            if reply.len() < 8 || reply[4..8] != transaction_id.to_be_bytes() {
+                // A late answer to an earlier request, or not a tracker at all.
+                continue;
+            }
+            let reply_action = u32::from_be_bytes(reply[..4].try_into().unwrap());
+            if reply_action == ERROR {
+                bail!("tracker error: {}", String::from_utf8_lossy(&reply[8..]));
+            }
+            ensure!(
+                reply_action == action,
+                "expected action {action} from tracker, got {reply_action}"
+            );
+            return Ok(reply[8..].to_vec());
+        }
+        if attempt < retransmits {
+            eprintln!(
+                "UDP tracker did not answer, retransmitting ({}/{retransmits})",
+                attempt + 1
+            );
+        }
+    }
+    bail!("UDP tracker did not answer")
+}
+
+fn transaction_id() -> u32 {
+    std::hash::RandomState::new().build_hasher().finish() as u32
+}
+
+#[test]
+fn retransmit_timeouts_double() {
+    assert_eq!(retransmit_timeout(0), Duration::from_secs(15));
+    assert_eq!(retransmit_timeout(1), Duration::from_secs(30));
+    assert_eq!(retransmit_timeout(8), Duration::from_secs(3840));
+    assert_eq!(retransmit_timeout(12), Duration::from_secs(3840));
+}
+
+#[tokio::test]
+async fn announce_to_udp_tracker() {
+    let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url =
+        reqwest::Url::parse(&format!("udp://{}/announce", tracker.local_addr().unwrap())).unwrap();
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        let (n, client) = tracker.recv_from(&mut buf).await.unwrap();
+        assert_eq!(n, 16);
+        assert_eq!(buf[..8], PROTOCOL_ID.to_be_bytes());
+        assert_eq!(buf[8..12], CONNECT.to_be_bytes());
+        let transaction = buf[12..16].to_vec();
+        // An answer to someone else's request must be ignored.
+        let mut stray = [0u8; 16];
+        stray[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        tracker.send_to(&stray, client).await.unwrap();
+        let mut reply = vec![0, 0, 0, 0];
+        reply.extend_from_slice(&transaction);
+        reply.extend_from_slice(&7u64.to_be_bytes());
+        tracker.send_to(&reply, client).await.unwrap();
+
+        let (n, client) = tracker.recv_from(&mut buf).await.unwrap();
+        assert_eq!(n, 98);
+        assert_eq!(buf[..8], 7u64.to_be_bytes());
+        assert_eq!(buf[8..12], ANNOUNCE.to_be_bytes());
+        assert_eq!(buf[16..36], [0xab; 20]);
+        assert_eq!(buf[36..56], *crate::peer::PeerId::session().as_bytes());
+        assert_eq!(buf[64..72], 100u64.to_be_bytes(), "left");
+        assert_eq!(buf[80..84], 2u32.to_be_bytes(), "started");
+        assert_eq!(buf[96..98], 6881u16.to_be_bytes());
+        let mut reply = vec![0, 0, 0, 1];
+        reply.extend_from_slice(&buf[12..16]);
+        reply.extend_from_slice(&1800u32.to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        tracker.send_to(&reply, client).await.unwrap();
+    });
+
+    let request = TrackerRequest {
+        event: Some(AnnounceEvent::Started),
+        ..TrackerRequest::new(100)
+    };
+    let response = announce(&url, [0xab; 20], request, 0)
+        .await
+        .expect("announce to mock UDP tracker");
+    server.await.unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(
+        response.peers.0,
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap(),
+        ]
+    );
+}
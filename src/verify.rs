@@ -0,0 +1,197 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::torrent::Torrent;
+
+/// Checks every piece of the file at `path` against the torrent's hashes,
+/// hashing batches of `batch` pieces in parallel on the blocking thread pool.
+/// `progress` is called with `(pieces_checked, total_pieces)` as batches finish.
+pub async fn verify_file(
+    t: &Torrent,
+    path: &Path,
+    batch: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<Vec<bool>> {
+    let total = t.info.pieces.0.len();
+    let mut batches = Vec::new();
+    for first in (0..total).step_by(batch.max(1)) {
+        let hashes = t.info.pieces.0[first..(first + batch.max(1)).min(total)].to_vec();
+        let path = path.to_path_buf();
+        let (piece_length, length) = (t.info.piece_length, t.length());
+        batches.push(tokio::task::spawn_blocking(move || {
+            verify_pieces(&path, first, &hashes, piece_length, length)
+        }));
+    }
+
+    let mut verified = Vec::with_capacity(total);
+    for batch in batches {
+        verified.extend(batch.await.context("join verification task")??);
+        progress(verified.len(), total);
+    }
+    Ok(verified)
+}
+
+/// Checks every piece of the file at `path` one after another.
+pub fn verify_file_serial(t: &Torrent, path: &Path) -> Result<Vec<bool>> {
+    verify_pieces(path, 0, &t.info.pieces.0, t.info.piece_length, t.length())
+}
+
+/// Checks every piece of the torrent's files at `path`, as laid out by
+/// [`Torrent::output_paths`], reading pieces across file boundaries. Pieces
+/// in missing or short files count as failed.
+pub async fn verify_files(t: &Torrent, path: &Path) -> Result<Vec<bool>> {
+    let mut disk = crate::disk::DiskReader::open(t, path).await?;
+    let mut buf = vec![0u8; t.info.piece_length];
+    let mut verified = Vec::with_capacity(t.info.pieces.0.len());
+    for (piece_i, offset, size, hash) in t.info.pieces_with_offsets() {
+        let buf = &mut buf[..size];
+        let read = disk
+            .read(offset as usize, buf)
+            .await
+            .with_context(|| format!("read piece {piece_i}"))?;
+        verified.push(read && <[u8; 20]>::from(Sha1::digest(buf)) == *hash);
+    }
+    Ok(verified)
+}
+
+/// Whether the torrent's files under `base`, laid out as [`Torrent::files`]
+/// lists them, are all there and every piece verifies. File sizes are checked
+/// first, so an incomplete download is usually detected without hashing.
+pub fn is_complete(t: &Torrent, base: &Path) -> bool {
+    let files = t.files();
+    let paths: Vec<_> = files
+        .iter()
+        .map(|file| base.join(file.path.iter().collect::<std::path::PathBuf>()))
+        .collect();
+    let sizes_match = files.iter().zip(&paths).all(|(file, path)| {
+        std::fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.len() == file.length as u64)
+    });
+    if !sizes_match {
+        return false;
+    }
+
+    let mut data: Box<dyn Read> = Box::new(std::io::empty());
+    for path in &paths {
+        let Ok(file) = std::fs::File::open(path) else {
+            return false;
+        };
+        data = Box::new(data.chain(std::io::BufReader::new(file)));
+    }
+    let mut buf = vec![0u8; t.info.piece_length];
+    t.info.pieces_with_offsets().all(|(_, _, size, hash)| {
+        let buf = &mut buf[..size];
+        data.read_exact(buf).is_ok() && <[u8; 20]>::from(Sha1::digest(buf)) == *hash
+    })
+}
+
+/// Hashes the pieces starting at `first`; pieces past the end of a short file
+/// count as failed.
+fn verify_pieces(
+    path: &Path,
+    first: usize,
+    hashes: &[[u8; 20]],
+    piece_length: usize,
+    length: usize,
+) -> Result<Vec<bool>> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    file.seek(SeekFrom::Start((first * piece_length) as u64))
+        .context("seek to first piece")?;
+
+    let mut buf = vec![0u8; piece_length];
+    hashes
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| {
+            let size = piece_length.min(length - (first + i) * piece_length);
+            let buf = &mut buf[..size];
+            match file.read_exact(buf) {
+                Ok(()) => Ok(<[u8; 20]>::from(Sha1::digest(buf)) == *hash),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+                Err(e) => Err(e).context("read piece"),
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn parallel_matches_serial() {
+    let data: Vec<u8> = (0..9 * 1024 + 100).map(|i| (i * 7 % 256) as u8).collect();
+    let t = crate::mock::torrent(1024, &data);
+
+    let mut corrupt = data.clone();
+    corrupt[1024 + 5] ^= 0xff;
+    corrupt[7 * 1024] ^= 0xff;
+    let path = crate::mock::temp_path("verify.bin");
+    std::fs::write(&path, &corrupt).unwrap();
+
+    let mut reports = Vec::new();
+    let parallel = verify_file(&t, &path, 3, |done, total| reports.push((done, total)))
+        .await
+        .unwrap();
+    let serial = verify_file_serial(&t, &path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut expected = vec![true; 10];
+    expected[1] = false;
+    expected[7] = false;
+    assert_eq!(parallel, expected);
+    assert_eq!(serial, expected);
+    assert_eq!(reports.last(), Some(&(10, 10)));
+}
+
+#[tokio::test]
+async fn corrupt_piece_across_files_fails_alone() {
+    let data: Vec<u8> = (0..5 * 1024 + 10).map(|i| (i * 5 % 256) as u8).collect();
+    let mut t = crate::mock::torrent(1024, &data);
+    t.info.keys = crate::torrent::Keys::MultiFile {
+        files: vec![
+            crate::torrent::File {
+                length: 1500,
+                path: vec![String::from("a")],
+            },
+            crate::torrent::File {
+                length: data.len() - 1500,
+                path: vec![String::from("sub"), String::from("b")],
+            },
+        ],
+    };
+    let base = crate::mock::temp_path("verify-files");
+    std::fs::create_dir_all(base.join("sub")).unwrap();
+    // Piece 1 spans both files; corrupt its part in the second.
+    let mut corrupt = data.clone();
+    corrupt[1600] ^= 0xff;
+    std::fs::write(base.join("a"), &corrupt[..1500]).unwrap();
+    std::fs::write(base.join("sub").join("b"), &corrupt[1500..]).unwrap();
+
+    let verified = verify_files(&t, &base).await.unwrap();
+    std::fs::remove_dir_all(&base).unwrap();
+    assert_eq!(verified, [true, false, true, true, true, true]);
+}
+
+#[test]
+fn complete_only_when_every_piece_verifies() {
+    let data: Vec<u8> = (0..5 * 1024 + 10).map(|i| (i * 3 % 256) as u8).collect();
+    let t = crate::mock::torrent(1024, &data);
+    let base = crate::mock::temp_path("complete");
+    std::fs::create_dir(&base).unwrap();
+    let path = base.join("mock.bin");
+
+    std::fs::write(&path, &data).unwrap();
+    assert!(is_complete(&t, &base));
+
+    std::fs::write(&path, &data[..4096]).unwrap();
+    assert!(!is_complete(&t, &base), "short file");
+
+    let mut corrupt = data.clone();
+    corrupt[2000] ^= 0xff;
+    std::fs::write(&path, &corrupt).unwrap();
+    assert!(!is_complete(&t, &base), "corrupt piece");
+
+    std::fs::remove_dir_all(&base).unwrap();
+    assert!(!is_complete(&t, &base), "missing file");
+}